@@ -1,10 +1,14 @@
+use bytes::BytesMut;
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 use http::response::Builder;
 use httparse::{ParserConfig, Request};
-use rinha_de_backend::infrastructure::server_impl::response::{Response, StatusCode};
-use rinha_de_backend::infrastructure::server_impl::server::parse_http;
+use rinha_de_backend::infrastructure::server_impl::response::{
+    Body, JsonResponse, Response, StatusCode,
+};
+use rinha_de_backend::infrastructure::server_impl::server::{parse_http, Method};
+use serde::Serialize;
 
-const SAMPLE: &[u8] = b"GET /somepath HTTP/1.1\nHost: ifconfig.me\nUser-Agent: curl/8.5.0\nAccept: */*\nContent-Type: text/html; charset=ISO-8859-4\r\n\r\n{\"json_key\": 10}";
+const SAMPLE: &[u8] = b"GET /somepath HTTP/1.1\nHost: ifconfig.me\nUser-Agent: curl/8.5.0\nAccept: */*\nContent-Type: text/html; charset=ISO-8859-4\nContent-Length: 16\r\n\r\n{\"json_key\": 10}";
 
 fn bench_http_parsing(c: &mut Criterion) {
     let mut group = c.benchmark_group("http_parse");
@@ -34,7 +38,7 @@ fn bench_http_response_build(c: &mut Criterion) {
                 status_code: StatusCode::Ok,
                 body: None,
             };
-            Response::into_http(black_box(response));
+            Response::into_http(black_box(response), true);
         })
     });
     group.bench_function(
@@ -43,13 +47,70 @@ fn bench_http_response_build(c: &mut Criterion) {
             c.iter(move || {
                 let response: Builder =
                     http::Response::builder().status(http::StatusCode::from_u16(200).unwrap());
-                Builder::body(black_box(response), black_box(())).unwrap();
+                Builder::body(black_box(response), ()).unwrap();
             })
         },
     );
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct SampleTransaction {
+    valor: i32,
+    tipo: &'static str,
+    descricao: &'static str,
+    realizada_em: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SampleStatement {
+    total: i32,
+    limite: u32,
+    ultimas_transacoes: Vec<SampleTransaction>,
+}
+
+fn sample_statement() -> SampleStatement {
+    let transaction = SampleTransaction {
+        valor: 10,
+        tipo: "c",
+        descricao: "descricao",
+        realizada_em: "2024-01-17T02:34:38.543030Z",
+    };
+
+    SampleStatement {
+        total: -9098,
+        limite: 100000,
+        ultimas_transacoes: vec![transaction; 10],
+    }
+}
+
+fn bench_http_response_json(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response_json");
+    let statement = sample_statement();
+
+    group.bench_function(BenchmarkId::new("Serialized to String", "statement"), |c| {
+        c.iter(|| {
+            // both sides own their DTO, as handlers do
+            let statement = black_box(statement.clone());
+            let body = simd_json::to_string(&statement).unwrap();
+            let response = Response::from_status_code(StatusCode::Ok, Body::from(body));
+            black_box(response.into_http(true));
+        })
+    });
+    group.bench_function(BenchmarkId::new("Serialized in place", "statement"), |c| {
+        let mut buf = BytesMut::with_capacity(1024);
+        c.iter(|| {
+            let response = JsonResponse::from(black_box(statement.clone())).0;
+            black_box(response.write_http(&mut buf, true, Method::GET));
+            buf.clear();
+        })
+    });
+}
+
 criterion_group!(http_parse, bench_http_parsing);
-criterion_group!(http_response, bench_http_response_build);
+criterion_group!(
+    http_response,
+    bench_http_response_build,
+    bench_http_response_json
+);
 
 criterion_main!(http_parse, http_response);
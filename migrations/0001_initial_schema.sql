@@ -0,0 +1,30 @@
+-- Accounts and their transactions.
+--
+-- Tables are unlogged: the data is disposable and write throughput matters more than durability.
+
+CREATE UNLOGGED TABLE account (
+	id integer NOT NULL,
+	balance integer NOT NULL DEFAULT 0,
+	credit_limit integer NOT NULL,
+	CONSTRAINT account_pk PRIMARY KEY (id),
+	CONSTRAINT credit_limit_not_negative CHECK (credit_limit >= 0),
+	CONSTRAINT balance_within_limit CHECK (balance >= -credit_limit)
+);
+
+CREATE UNLOGGED TABLE transaction (
+	id bigint GENERATED ALWAYS AS IDENTITY,
+	account_id integer NOT NULL,
+	amount integer NOT NULL,
+	kind char(1) NOT NULL,
+	description text NOT NULL,
+	created_on timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	CONSTRAINT transaction_pk PRIMARY KEY (id),
+	CONSTRAINT account_fk FOREIGN KEY (account_id)
+		REFERENCES account (id) ON DELETE CASCADE ON UPDATE CASCADE,
+	CONSTRAINT amount_not_zero CHECK (amount <> 0),
+	CONSTRAINT kind_matches_amount CHECK ((kind = 'c' AND amount > 0) OR (kind = 'd' AND amount < 0))
+);
+
+COMMENT ON COLUMN transaction.amount IS 'Signed transaction amount, negative for debits';
+
+CREATE INDEX transaction_account_created_on_idx ON transaction (account_id, created_on DESC);
@@ -1,2 +1,2 @@
 INSERT INTO account (id, credit_limit, balance)
-VALUES (1, 100000, 0), (2, 80000, 0), (3, 1000000, 0), (4, 10000000, 0), (5, 500000, 0);
\ No newline at end of file
+VALUES (1, 100000, 0), (2, 80000, 0), (3, 1000000, 0), (4, 10000000, 0), (5, 500000, 0);
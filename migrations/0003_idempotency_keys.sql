@@ -0,0 +1,16 @@
+-- Outcomes of transactions submitted with an Idempotency-Key, replayed to retries.
+--
+-- The outcome columns stay null until the transaction is handled, in the same database
+-- transaction that claimed the key.
+
+CREATE UNLOGGED TABLE idempotency_key (
+	account_id integer NOT NULL,
+	key text NOT NULL,
+	created_on timestamp NOT NULL,
+	balance integer,
+	credit_limit integer,
+	rejection text,
+	CONSTRAINT idempotency_key_pk PRIMARY KEY (account_id, key)
+);
+
+CREATE INDEX idempotency_key_created_on_idx ON idempotency_key (created_on);
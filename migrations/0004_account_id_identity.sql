@@ -0,0 +1,6 @@
+-- Accounts opened through the API get their id from Postgres, after the seeded ones.
+
+ALTER TABLE account ALTER COLUMN id ADD GENERATED BY DEFAULT AS IDENTITY;
+
+SELECT setval(pg_get_serial_sequence('account', 'id'), coalesce(max(id), 0) + 1, false)
+  FROM account;
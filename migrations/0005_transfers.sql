@@ -0,0 +1,7 @@
+-- Transfers are a debit and a credit in two accounts sharing a transfer_id.
+
+CREATE SEQUENCE transfer_id_seq AS bigint;
+
+ALTER TABLE transaction ADD COLUMN transfer_id bigint;
+
+CREATE INDEX transaction_transfer_id_idx ON transaction (transfer_id) WHERE transfer_id IS NOT NULL;
@@ -0,0 +1,13 @@
+-- Reversals reference the transaction they compensate, at most one per transaction.
+--
+-- A forced reversal may take the balance past the credit limit, so the limit is no longer a
+-- table constraint, the statements changing balances check it instead.
+
+ALTER TABLE account DROP CONSTRAINT balance_within_limit;
+
+ALTER TABLE transaction ADD COLUMN reverses bigint;
+
+ALTER TABLE transaction ADD CONSTRAINT reverses_fk FOREIGN KEY (reverses)
+	REFERENCES transaction (id) ON DELETE CASCADE;
+
+CREATE UNIQUE INDEX transaction_reverses_idx ON transaction (reverses) WHERE reverses IS NOT NULL;
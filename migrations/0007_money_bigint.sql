@@ -0,0 +1,11 @@
+-- Amounts are cents in a bigint, matching Money.
+
+ALTER TABLE account
+	ALTER COLUMN balance TYPE bigint,
+	ALTER COLUMN credit_limit TYPE bigint;
+
+ALTER TABLE transaction ALTER COLUMN amount TYPE bigint;
+
+ALTER TABLE idempotency_key
+	ALTER COLUMN balance TYPE bigint,
+	ALTER COLUMN credit_limit TYPE bigint;
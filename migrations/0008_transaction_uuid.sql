@@ -0,0 +1,39 @@
+-- Transactions are keyed by the UUIDv7 ids the service generates, instead of a sequence.
+--
+-- Existing transactions get ids built from their creation time and old id, so they keep sorting
+-- the same way. Idempotency keys remember the transaction they created, to replay its id.
+
+ALTER TABLE transaction
+	ADD COLUMN uuid uuid,
+	ADD COLUMN reverses_uuid uuid;
+
+UPDATE transaction
+   SET uuid = (lpad(to_hex((extract(epoch FROM created_on) * 1000)::bigint), 12, '0')
+              || '7000' || '8' || lpad(to_hex(id), 15, '0'))::uuid;
+
+UPDATE transaction
+   SET reverses_uuid = original.uuid
+  FROM transaction original
+ WHERE transaction.reverses = original.id;
+
+DROP INDEX transaction_reverses_idx;
+
+ALTER TABLE transaction
+	DROP CONSTRAINT reverses_fk,
+	DROP CONSTRAINT transaction_pk,
+	DROP COLUMN reverses,
+	DROP COLUMN id;
+
+ALTER TABLE transaction RENAME COLUMN uuid TO id;
+
+ALTER TABLE transaction RENAME COLUMN reverses_uuid TO reverses;
+
+ALTER TABLE transaction
+	ALTER COLUMN id SET NOT NULL,
+	ADD CONSTRAINT transaction_pk PRIMARY KEY (id),
+	ADD CONSTRAINT reverses_fk FOREIGN KEY (reverses)
+		REFERENCES transaction (id) ON DELETE CASCADE;
+
+CREATE UNIQUE INDEX transaction_reverses_idx ON transaction (reverses) WHERE reverses IS NOT NULL;
+
+ALTER TABLE idempotency_key ADD COLUMN transaction_id uuid;
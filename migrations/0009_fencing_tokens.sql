@@ -0,0 +1,3 @@
+-- Highest lock fencing token a write to the account carried, writes with older ones are refused.
+
+ALTER TABLE account ADD COLUMN fencing_token bigint NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- Statements page through (created_on, id), the id tells apart transactions created in the same
+-- microsecond.
+
+DROP INDEX transaction_account_created_on_idx;
+
+CREATE INDEX transaction_account_created_on_idx ON transaction (account_id, created_on DESC, id DESC);
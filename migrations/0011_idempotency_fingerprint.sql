@@ -0,0 +1,5 @@
+-- Idempotency keys remember what the request asked for, so a key reused for another request is
+-- refused instead of replaying an unrelated outcome. Keys claimed before this have none, and keep
+-- replaying for any request until they expire.
+
+ALTER TABLE idempotency_key ADD COLUMN fingerprint bigint;
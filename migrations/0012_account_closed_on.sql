@@ -0,0 +1,10 @@
+-- Closed accounts are kept, as transfers and reversals in other accounts still refer to their
+-- history, and refuse any further writes. Deleting an account no longer takes its transactions
+-- along.
+
+ALTER TABLE account ADD COLUMN closed_on timestamp;
+
+ALTER TABLE transaction
+	DROP CONSTRAINT account_fk,
+	ADD CONSTRAINT account_fk FOREIGN KEY (account_id)
+		REFERENCES account (id) ON DELETE RESTRICT ON UPDATE CASCADE;
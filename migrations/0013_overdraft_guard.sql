@@ -0,0 +1,20 @@
+-- The credit limit is enforced by the database again, whichever statement writes the balance.
+--
+-- A write may only leave the balance past the limit if it isn't worse off than before, or if its
+-- transaction opted in with `set_config('rinha.allow_overdraft', 'on', true)`, which only forced
+-- reversals do.
+
+CREATE FUNCTION balance_within_limit() RETURNS trigger AS $$
+BEGIN
+	IF NEW.balance < -NEW.credit_limit
+	   AND (NEW.balance < OLD.balance OR NEW.credit_limit < OLD.credit_limit)
+	   AND current_setting('rinha.allow_overdraft', true) IS DISTINCT FROM 'on' THEN
+		RAISE EXCEPTION 'account % balance % past its credit limit %', NEW.id, NEW.balance, NEW.credit_limit
+			USING ERRCODE = 'check_violation';
+	END IF;
+	RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER balance_within_limit BEFORE UPDATE OF balance, credit_limit ON account
+	FOR EACH ROW EXECUTE FUNCTION balance_within_limit();
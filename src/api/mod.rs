@@ -1,16 +1,26 @@
-use crate::application::adapters::{StatementDTO, TransactionDTO};
+use crate::application::adapters::{
+    transaction_from_json, transfer_from_json, AccountDTO, BalanceDTO, CreditLimitDTO,
+    StatementDTO, TransactionDTO,
+};
 use crate::application::cache::AccountCache;
-use crate::application::repositories::TransactionRepository;
-use crate::application::ServerData;
+use crate::application::repositories::{Idempotent, TransactionRepository};
+use crate::application::{ServerData, ServiceConfig, StatementCursor, StatementPage};
 use crate::domain::account::Account;
-use crate::domain::transaction::Transaction;
+use crate::domain::errors::TransactionError;
+use crate::domain::money::Money;
+use crate::domain::transaction::{Transaction, TransactionDescription, TransactionId};
+use crate::infrastructure::lock::{
+    AccountLockGuard, AccountLocks, DistributedLock, LockError, LockGuard,
+};
+use crate::infrastructure::server_impl::errors::HttpError;
 use crate::infrastructure::server_impl::request::Request;
 use crate::infrastructure::server_impl::response::{JsonResponse, Response, StatusCode};
-use crate::infrastructure::server_impl::server::Method;
-use crate::AnyResult;
-use eyre::bail;
+use crate::infrastructure::server_impl::server::Header;
+use compact_str::CompactString;
 use fnv::FnvHashMap;
 use std::sync::{Arc, Mutex};
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
 
 pub mod input_types;
 
@@ -18,54 +28,54 @@ pub async fn statement_route(
     server_data: &ServerData,
     req: Request<'_>,
     client_id: i32,
-) -> AnyResult<Response> {
-    // let body = req.body.unwrap();
-
-    if req.method != Method::GET {
-        bail!("Only GET available.")
+) -> Result<Response, HttpError> {
+    let query = req.query();
+    let page = StatementPage {
+        limit: query
+            .parse("limit")?
+            .unwrap_or(StatementPage::DEFAULT_LIMIT),
+        before: query.parse("before")?,
+        from: query.parse_with("from", parse_date)?,
+        to: query.parse_with("to", parse_date)?,
+    };
+    if !(1..=StatementPage::MAX_LIMIT).contains(&page.limit) {
+        return Err(HttpError::BadRequest("Invalid limit."));
     }
 
-    let service = BankAccountService {
-        re_conn: server_data.re_conn.clone(),
-        pg_conn: server_data.pg_pool.clone(),
-    };
+    let service = BankAccountService::new(server_data);
 
-    let res = service
-        .query(AccountQueries::Statement { account: client_id })
-        .await;
+    let (acc, transactions, next_cursor) = service
+        .query(AccountQueries::Statement {
+            account: client_id,
+            page,
+        })
+        .await?;
 
-    let a = JsonResponse::from::<StatementDTO>(StatementDTO::from_other(res));
+    let statement =
+        StatementDTO::from_other((acc, transactions.into_iter())).with_next_cursor(next_cursor);
+    let a = JsonResponse::from::<StatementDTO>(statement);
     Ok(a.0)
 }
 
+fn parse_date(date: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(date, &Iso8601::PARSING).ok()
+}
+
 pub async fn transaction_route(
     server_data: &ServerData,
     req: Request<'_>,
     client_id: i32,
-) -> AnyResult<Response> {
-    if req.method != Method::POST {
-        eprintln!("body needed.");
-        bail!("Only POST available.");
-    }
-
-    let body = req
-        .body
-        .ok_or_else(|| Response::from_status_code(StatusCode::NotFound, "no-body".to_string()))
-        .unwrap();
-
-    let mapping = serde_json::from_slice::<TransactionDTO>(body)
-        .map_err(|_| ())
-        .and_then(|c| c.try_into());
-
-    let transaction: Transaction = match mapping {
-        Ok(trans) => trans,
-        Err(_) => {
-            return Ok(Response::from_status_code(
-                StatusCode::NotFound,
-                "error-mapping".to_string(),
-            ));
+) -> Result<Response, HttpError> {
+    let idempotency_key = match req.headers[Header::IDEMPOTENCY_KEY].trim() {
+        "" => None,
+        key if key.len() > MAX_IDEMPOTENCY_KEY_LEN => {
+            return Err(HttpError::BadRequest("Invalid Idempotency-Key."))
         }
+        key => Some(CompactString::from(key)),
     };
+    let body = req.body.ok_or(HttpError::BadRequest("Body needed."))?;
+
+    let transaction = transaction_from_json(&body, &server_data.config.description_rules)?;
 
     // read model
     // - read last 10 transactions and balance from redis
@@ -86,31 +96,156 @@ pub async fn transaction_route(
     let command = AccountCommands::HandleMoney {
         account: client_id,
         transaction,
+        idempotency_key,
     };
 
-    let bank_service = BankAccountService {
-        re_conn: server_data.re_conn.clone(),
-        pg_conn: server_data.pg_pool.clone(),
-    };
-    let res = bank_service.handler(command).await;
+    let bank_service = BankAccountService::new(server_data);
+    let (acc, transaction) = bank_service.handler(command).await?;
+
+    let a = JsonResponse::from::<BalanceDTO>(BalanceDTO::from(acc).with_transaction(transaction));
+    Ok(a.0)
+}
+
+pub async fn transaction_lookup_route(
+    server_data: &ServerData,
+    _req: Request<'_>,
+    client_id: i32,
+    transaction_id: TransactionId,
+) -> Result<Response, HttpError> {
+    let transaction = BankAccountService::new(server_data)
+        .get_transaction(client_id, transaction_id)
+        .await?;
+
+    let a = JsonResponse::from::<TransactionDTO>(TransactionDTO::from(transaction));
+    Ok(a.0)
+}
+
+pub async fn open_account_route(
+    server_data: &ServerData,
+    req: Request<'_>,
+) -> Result<Response, HttpError> {
+    let credit_limit = parse_credit_limit(req)?;
+
+    let (acc, _) = BankAccountService::new(server_data)
+        .handler(AccountCommands::Open { credit_limit })
+        .await?;
+
+    let mut response = JsonResponse::from::<AccountDTO>(AccountDTO::from(acc)).0;
+    response.status_code = StatusCode::Created;
+    Ok(response.with_header(
+        Header::LOCATION,
+        compact_str::format_compact!("/clientes/{}", acc.id),
+    ))
+}
+
+pub async fn account_route(
+    server_data: &ServerData,
+    _req: Request<'_>,
+    client_id: i32,
+) -> Result<Response, HttpError> {
+    let acc = BankAccountService::new(server_data)
+        .get_account(client_id)
+        .await?;
+
+    let a = JsonResponse::from::<AccountDTO>(AccountDTO::from(acc));
+    Ok(a.0)
+}
+
+pub async fn credit_limit_route(
+    server_data: &ServerData,
+    req: Request<'_>,
+    client_id: i32,
+) -> Result<Response, HttpError> {
+    let credit_limit = parse_credit_limit(req)?;
 
-    // if res.is_err() {
-    //     return Ok(Response::from_status_code(
-    //         StatusCode::UnprocessableEntity,
-    //         None,
-    //     ));
-    // }
+    let (acc, _) = BankAccountService::new(server_data)
+        .handler(AccountCommands::ChangeLimit {
+            account: client_id,
+            credit_limit,
+        })
+        .await?;
 
-    let a =
-        JsonResponse::from::<TransactionDTO>(TransactionDTO::from(Transaction::generate(1, None)));
+    let a = JsonResponse::from::<AccountDTO>(AccountDTO::from(acc));
     Ok(a.0)
 }
 
-pub type AccountMapStorage = Arc<FnvHashMap<i32, (u32, Mutex<Account>)>>;
+pub async fn close_account_route(
+    server_data: &ServerData,
+    _req: Request<'_>,
+    client_id: i32,
+) -> Result<Response, HttpError> {
+    BankAccountService::new(server_data)
+        .handler(AccountCommands::Close { account: client_id })
+        .await?;
+
+    Ok(Response::from_status_code(StatusCode::NoContent, None))
+}
+
+fn parse_credit_limit(req: Request<'_>) -> Result<Money, HttpError> {
+    let body = req.body.ok_or(HttpError::BadRequest("Body needed."))?;
+
+    let credit_limit = serde_json::from_slice::<CreditLimitDTO>(&body)
+        .map_err(|_| HttpError::BadRequest("Invalid account payload."))?
+        .credit_limit;
+    if credit_limit < 0 {
+        return Err(HttpError::UnprocessableEntity("Invalid limit."));
+    }
+
+    Ok(Money::from_cents(credit_limit))
+}
+
+/// `?forcar=true` reverses even past the credit limit.
+pub async fn reversal_route(
+    server_data: &ServerData,
+    req: Request<'_>,
+    client_id: i32,
+    transaction_id: TransactionId,
+) -> Result<Response, HttpError> {
+    let force = req.query().parse("forcar")?.unwrap_or(false);
+
+    let (acc, reversal) = BankAccountService::new(server_data)
+        .handler(AccountCommands::Reverse {
+            account: client_id,
+            transaction: transaction_id,
+            force,
+        })
+        .await?;
+
+    let a = JsonResponse::from::<BalanceDTO>(BalanceDTO::from(acc).with_transaction(reversal));
+    Ok(a.0)
+}
+
+pub async fn transfer_route(
+    server_data: &ServerData,
+    req: Request<'_>,
+) -> Result<Response, HttpError> {
+    let body = req.body.ok_or(HttpError::BadRequest("Body needed."))?;
+
+    let transfer = transfer_from_json(&body, &server_data.config.description_rules)?;
+
+    let (acc, debit) = BankAccountService::new(server_data)
+        .handler(AccountCommands::Transfer {
+            from: transfer.from,
+            to: transfer.to,
+            amount: transfer.amount,
+            description: transfer.description,
+        })
+        .await?;
+
+    let a = JsonResponse::from::<BalanceDTO>(BalanceDTO::from(acc).with_transaction(debit));
+    Ok(a.0)
+}
+
+/// Longest `Idempotency-Key` accepted, keys are generated ids in practice.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+pub type AccountMapStorage = Arc<FnvHashMap<i32, (Money, Mutex<Account>)>>;
 
 struct BankAccountService {
     re_conn: redis::aio::ConnectionManager,
     pg_conn: deadpool_postgres::Pool,
+    config: ServiceConfig,
+    locks: AccountLocks,
     // storage: AccountMapStorage,
 }
 
@@ -119,59 +254,589 @@ enum AccountCommands {
     HandleMoney {
         account: i32,
         transaction: Transaction,
+        /// Replays the outcome of an earlier submission with the same key instead.
+        idempotency_key: Option<CompactString>,
+    },
+    /// Returns the debited account, the other one's balance is none of the sender's business.
+    Transfer {
+        from: i32,
+        to: i32,
+        amount: Money,
+        description: TransactionDescription,
+    },
+    Reverse {
+        account: i32,
+        transaction: TransactionId,
+        /// Reverse even if the balance ends up past the credit limit.
+        force: bool,
+    },
+    Open {
+        credit_limit: Money,
+    },
+    ChangeLimit {
+        account: i32,
+        credit_limit: Money,
+    },
+    Close {
+        account: i32,
     },
 }
 
 #[derive(Debug)]
 enum AccountQueries {
-    Statement { account: i32 },
+    Statement { account: i32, page: StatementPage },
 }
 
 impl BankAccountService {
-    async fn query(&self, command: AccountQueries) -> (Account, impl Iterator<Item = Transaction>) {
+    fn new(server_data: &ServerData) -> Self {
+        Self {
+            re_conn: server_data.re_conn.clone(),
+            pg_conn: server_data.pg_pool.clone(),
+            config: server_data.config,
+            locks: server_data.locks.clone(),
+        }
+    }
+
+    /// Served from the cache, accounts missing there are read from Postgres and cached.
+    async fn get_account(&self, user: i32) -> Result<Account, HttpError> {
+        let trans_cache = AccountCache {
+            re_conn: self.re_conn.clone(),
+        };
+        if let Some((acc, _, _)) = trans_cache.get_account(user, 0).await? {
+            return Ok(acc);
+        }
+
+        let trans_repo = TransactionRepository {
+            conn: self.pg_conn.clone(),
+        };
+        let acc = trans_repo
+            .get_account(user)
+            .await?
+            .ok_or(HttpError::NotFound("Account not found."))?;
+        trans_cache.save_account(user, &acc, None).await?;
+
+        Ok(acc)
+    }
+
+    /// Read from Postgres, the cached stream can't be searched by id.
+    async fn get_transaction(
+        &self,
+        user: i32,
+        transaction: TransactionId,
+    ) -> Result<Transaction, HttpError> {
+        let trans_repo = TransactionRepository {
+            conn: self.pg_conn.clone(),
+        };
+
+        let transaction = trans_repo
+            .get_transaction(user, transaction)
+            .await?
+            .ok_or(TransactionError::NotFound)?;
+        Ok(transaction)
+    }
+
+    /// Statements are served from the cached stream of recent transactions whenever it holds the
+    /// whole page, older history comes from Postgres.
+    async fn query(
+        &self,
+        command: AccountQueries,
+    ) -> Result<(Account, Vec<Transaction>, Option<StatementCursor>), HttpError> {
         match command {
-            AccountQueries::Statement { account: user } => {
+            AccountQueries::Statement {
+                account: user,
+                page,
+            } => {
                 let trans_cache = AccountCache {
                     re_conn: self.re_conn.clone(),
                 };
 
-                let res = trans_cache.get_account(user, true).await;
-                (res.0, res.1.unwrap())
+                let unfiltered = page.before.is_none() && page.from.is_none() && page.to.is_none();
+                let fetch = if unfiltered {
+                    (page.limit + 1).min(AccountCache::CACHED_TRANSACTIONS)
+                } else {
+                    AccountCache::CACHED_TRANSACTIONS
+                };
+
+                let (acc, mut cached, seeded) = match trans_cache.get_account(user, fetch).await? {
+                    Some(cached) => cached,
+                    // Redis lost the account, Postgres has everything
+                    None => (self.get_account(user).await?, Vec::new(), false),
+                };
+                // a seeded stream shorter than asked for was never trimmed, it holds the whole
+                // history
+                let whole_history = seeded && cached.len() < fetch;
+                // the stream is in the order locks were taken, transactions are stamped before
+                // waiting on them
+                cached.sort_unstable_by(|a, b| StatementCursor::of(b).cmp(&StatementCursor::of(a)));
+
+                let mut transactions = cached
+                    .into_iter()
+                    .filter(|transaction| page.contains(transaction))
+                    .take(page.limit + 1)
+                    .collect::<Vec<_>>();
+
+                if transactions.len() <= page.limit && !whole_history {
+                    let trans_repo = TransactionRepository {
+                        conn: self.pg_conn.clone(),
+                    };
+                    transactions = trans_repo.get_transactions(user, &page).await?;
+                }
+
+                let next_cursor = if transactions.len() > page.limit {
+                    transactions.truncate(page.limit);
+                    transactions.last().map(StatementCursor::of)
+                } else {
+                    None
+                };
+
+                Ok((acc, transactions, next_cursor))
             }
         }
     }
-    async fn handler(&self, command: AccountCommands) -> AnyResult<()> {
+    /// Returns the [Account] as it is after the command, and the id of the transaction saved
+    /// by commands that move money.
+    async fn handler(
+        &self,
+        command: AccountCommands,
+    ) -> Result<(Account, Option<TransactionId>), HttpError> {
         match command {
             AccountCommands::HandleMoney {
                 account: user,
                 transaction,
+                idempotency_key,
+            } => {
+                let guard = self.locks.acquire(user).await?;
+
+                let result = self
+                    .handle_money(user, &transaction, idempotency_key, &guard)
+                    .await;
+                release(user, guard).await;
+
+                result
+            }
+            AccountCommands::Transfer {
+                from,
+                to,
+                amount,
+                description,
+            } => {
+                // in id order, so transfers going opposite ways can't deadlock
+                let (first, second) = (from.min(to), from.max(to));
+                let first_guard = self.locks.acquire(first).await?;
+                let second_guard = match self.locks.acquire(second).await {
+                    Ok(guard) => guard,
+                    Err(err) => {
+                        release(first, first_guard).await;
+                        return Err(err.into());
+                    }
+                };
+                let (from_guard, to_guard) = if from == first {
+                    (first_guard, second_guard)
+                } else {
+                    (second_guard, first_guard)
+                };
+
+                let result = self
+                    .transfer(from, to, amount, description, &from_guard, &to_guard)
+                    .await;
+                release(from, from_guard).await;
+                release(to, to_guard).await;
+
+                result
+            }
+            AccountCommands::Reverse {
+                account: user,
+                transaction,
+                force,
             } => {
+                let guard = self.locks.acquire(user).await?;
+
+                let result = self.reverse(user, transaction, force, &guard).await;
+                release(user, guard).await;
+
+                result
+            }
+            AccountCommands::Open { credit_limit } => {
                 let trans_repo = TransactionRepository {
                     conn: self.pg_conn.clone(),
                 };
+                let trans_cache = AccountCache {
+                    re_conn: self.re_conn.clone(),
+                };
+
+                let acc = trans_repo.create_account(credit_limit).await?;
+                trans_cache.seed_account(acc.id, &acc, &[]).await?;
 
+                Ok((acc, None))
+            }
+            AccountCommands::ChangeLimit {
+                account: user,
+                credit_limit,
+            } => {
+                let trans_repo = TransactionRepository {
+                    conn: self.pg_conn.clone(),
+                };
                 let trans_cache = AccountCache {
                     re_conn: self.re_conn.clone(),
                 };
 
-                // let redis_lock = RedisLock::new(self.re_conn.clone(), user, 100);
+                let acc = trans_repo.set_credit_limit(user, credit_limit).await??;
+                trans_cache.save_account(user, &acc, None).await?;
 
-                let (acc, _) = trans_cache.get_account(user, false).await;
-                let acc = acc
-                    .add_transaction(&transaction)
-                    .or_else(|_| bail!("no credits bro"))?;
+                Ok((acc, None))
+            }
+            AccountCommands::Close { account: user } => {
+                let trans_repo = TransactionRepository {
+                    conn: self.pg_conn.clone(),
+                };
+                let trans_cache = AccountCache {
+                    re_conn: self.re_conn.clone(),
+                };
 
-                {
-                    // let guard = redis_lock.acquire().await.unwrap();
-                    trans_repo.save_and_get_balance(user, &transaction).await?;
-                    trans_cache
-                        .save_account(user, &acc, Some(&transaction))
-                        .await;
-                    // guard.release().await;
-                }
+                let acc = trans_repo.close_account(user).await??;
+                trans_cache.remove_account(user).await?;
 
-                Ok(())
+                Ok((acc, None))
             }
         }
     }
+
+    /// Moves money between accounts under both their locks, see [AccountCommands::Transfer].
+    async fn transfer(
+        &self,
+        from: i32,
+        to: i32,
+        amount: Money,
+        description: TransactionDescription,
+        from_guard: &AccountLockGuard,
+        to_guard: &AccountLockGuard,
+    ) -> Result<(Account, Option<TransactionId>), HttpError> {
+        let (mut debit, mut credit) = Transaction::transfer(amount, description);
+        let fencing_tokens = (
+            from_guard.fencing_token().map(|token| token.get()),
+            to_guard.fencing_token().map(|token| token.get()),
+        );
+
+        let trans_repo = TransactionRepository {
+            conn: self.pg_conn.clone(),
+        };
+        let trans_cache = AccountCache {
+            re_conn: self.re_conn.clone(),
+        };
+
+        let (from_acc, to_acc) = trans_repo
+            .save_transfer(
+                from,
+                to,
+                &mut debit,
+                &mut credit,
+                fencing_tokens,
+                from_guard.client(),
+            )
+            .await??;
+        // the write went through the lock on `from`
+        from_guard.commit().await?;
+
+        if still_held(from, from_guard, &trans_cache).await? {
+            trans_cache
+                .save_account(from, &from_acc, Some(&debit))
+                .await?;
+        }
+        if still_held(to, to_guard, &trans_cache).await? {
+            trans_cache.save_account(to, &to_acc, Some(&credit)).await?;
+        }
+
+        Ok((from_acc, Some(debit.id)))
+    }
+
+    /// Reverses a transaction under the account lock, see [AccountCommands::Reverse].
+    async fn reverse(
+        &self,
+        user: i32,
+        transaction: TransactionId,
+        force: bool,
+        guard: &AccountLockGuard,
+    ) -> Result<(Account, Option<TransactionId>), HttpError> {
+        let fencing_token = guard.fencing_token().map(|token| token.get());
+
+        let trans_repo = TransactionRepository {
+            conn: self.pg_conn.clone(),
+        };
+        let trans_cache = AccountCache {
+            re_conn: self.re_conn.clone(),
+        };
+
+        let (acc, reversal) = trans_repo
+            .save_reversal(user, transaction, force, fencing_token, guard.client())
+            .await??;
+        guard.commit().await?;
+        if still_held(user, guard, &trans_cache).await? {
+            trans_cache
+                .save_account(user, &acc, Some(&reversal))
+                .await?;
+        }
+
+        Ok((acc, Some(reversal.id)))
+    }
+
+    /// Saves a credit or debit, see [AccountCommands::HandleMoney].
+    async fn handle_money(
+        &self,
+        user: i32,
+        transaction: &Transaction,
+        idempotency_key: Option<CompactString>,
+        guard: &AccountLockGuard,
+    ) -> Result<(Account, Option<TransactionId>), HttpError> {
+        let fencing_token = guard.fencing_token().map(|token| token.get());
+        let trans_repo = TransactionRepository {
+            conn: self.pg_conn.clone(),
+        };
+
+        let trans_cache = AccountCache {
+            re_conn: self.re_conn.clone(),
+        };
+
+        let Some(key) = idempotency_key else {
+            // Postgres enforces the credit limit, the cached account may be stale
+            let acc = trans_repo
+                .save_and_get_balance(user, transaction, fencing_token, guard.client())
+                .await??;
+            guard.commit().await?;
+            if still_held(user, guard, &trans_cache).await? {
+                trans_cache
+                    .save_account(user, &acc, Some(transaction))
+                    .await?;
+            }
+            return Ok((acc, Some(transaction.id)));
+        };
+
+        let fingerprint = transaction.fingerprint();
+        if let Some((first, outcome)) = trans_cache.get_outcome(user, &key).await? {
+            if first != fingerprint {
+                return Err(TransactionError::IdempotencyKeyReused.into());
+            }
+            return Ok(outcome?);
+        }
+
+        let retention = self.config.idempotency_retention;
+        let idempotent = trans_repo
+            .save_idempotent(
+                user,
+                &key,
+                transaction,
+                fencing_token,
+                retention,
+                guard.client(),
+            )
+            .await??;
+        // the outcome is cached for replays only once it's there for good
+        guard.commit().await?;
+        if let Idempotent::Applied(Ok((acc, _))) = idempotent {
+            if still_held(user, guard, &trans_cache).await? {
+                trans_cache
+                    .save_account(user, &acc, Some(transaction))
+                    .await?;
+            }
+        }
+
+        let outcome = idempotent.outcome();
+        trans_cache
+            .save_outcome(user, &key, fingerprint, &outcome, retention)
+            .await?;
+
+        Ok(outcome?)
+    }
+}
+
+/// Releases the lock on `account` once the writes made under it are done, whatever their outcome.
+///
+/// They're committed by then, see [LockGuard::commit], a lock that can't be released merely
+/// lingers until it expires or its connection closes.
+async fn release(account: i32, guard: AccountLockGuard) {
+    if let Err(err) = guard.release().await {
+        eprintln!("account {account} lock not released; err = {err}");
+    }
+}
+
+/// Extends the lock on `account` once its write is in Postgres, for the cache update to follow.
+///
+/// A lock that expired meanwhile may have a new holder caching newer writes, which ours must not
+/// overwrite: the account is dropped from the cache instead, to be read from Postgres again.
+async fn still_held(
+    account: i32,
+    guard: &AccountLockGuard,
+    trans_cache: &AccountCache,
+) -> Result<bool, HttpError> {
+    match guard.extend().await {
+        Ok(()) => Ok(true),
+        Err(LockError::Lost) => {
+            eprintln!("account {account} lock expired before its cache update");
+            trans_cache.remove_account(account).await?;
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::migrations::run_migrations;
+    use crate::infrastructure::lock::LockBackend;
+    use crate::infrastructure::server_impl::server::{match_routes, parse_http};
+    use deadpool_postgres::Runtime;
+    use tokio_postgres::NoTls;
+
+    async fn server_data(lock_backend: LockBackend) -> ServerData {
+        let mut cfg = deadpool_postgres::Config::new();
+        cfg.url = Some(std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is needed"));
+        let pg_pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+        run_migrations(&pg_pool).await.unwrap();
+
+        let redis_url = std::env::var("TEST_REDIS_URL").expect("TEST_REDIS_URL is needed");
+        let client = redis::Client::open(redis_url).unwrap();
+        let re_conn = redis::aio::ConnectionManager::new(client).await.unwrap();
+
+        let config = ServiceConfig {
+            lock_backend,
+            ..Default::default()
+        };
+        let locks = AccountLocks::new(
+            lock_backend,
+            &re_conn,
+            &pg_pool,
+            config.lock_ttl,
+            config.lock_retry,
+        );
+        ServerData {
+            re_conn,
+            pg_pool,
+            config,
+            locks,
+        }
+    }
+
+    async fn send(server_data: &ServerData, raw: &str) -> Result<Response, HttpError> {
+        let (request, _) = parse_http(raw.as_bytes()).unwrap().unwrap();
+        match_routes(server_data, request).await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL and TEST_REDIS_URL"]
+    async fn closed_accounts_read_as_missing() {
+        let server_data = server_data(LockBackend::InProcess).await;
+        let repo = TransactionRepository {
+            conn: server_data.pg_pool.clone(),
+        };
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let read = format!("GET /clientes/{} HTTP/1.1\r\n\r\n", acc.id);
+        let statement = format!("GET /clientes/{}/extrato HTTP/1.1\r\n\r\n", acc.id);
+        // cached by the first read
+        assert!(send(&server_data, &read).await.is_ok());
+
+        let close = format!("DELETE /clientes/{} HTTP/1.1\r\n\r\n", acc.id);
+        let response = send(&server_data, &close).await.unwrap();
+        assert_eq!(response.status_code, StatusCode::NoContent);
+
+        for request in [&read, &statement, &read] {
+            let err = send(&server_data, request).await.unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::NotFound, "{request}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL and TEST_REDIS_URL"]
+    async fn cached_statements_page_by_cursor() {
+        let server_data = server_data(LockBackend::InProcess).await;
+        let repo = TransactionRepository {
+            conn: server_data.pg_pool.clone(),
+        };
+        let cache = AccountCache {
+            re_conn: server_data.re_conn.clone(),
+        };
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+        cache.seed_account(acc.id, &acc, &[]).await.unwrap();
+
+        // stamped first, but its lock was taken last
+        let late = Transaction::generate(10, None);
+        let early = Transaction::generate(20, None);
+        assert!(StatementCursor::of(&late) < StatementCursor::of(&early));
+        cache
+            .save_account(acc.id, &acc, Some(&early))
+            .await
+            .unwrap();
+        cache.save_account(acc.id, &acc, Some(&late)).await.unwrap();
+
+        let service = BankAccountService::new(&server_data);
+        let mut page = StatementPage {
+            limit: 1,
+            ..Default::default()
+        };
+        let mut seen = Vec::new();
+        loop {
+            let statement = AccountQueries::Statement {
+                account: acc.id,
+                page,
+            };
+            let (_, transactions, next) = service.query(statement).await.unwrap();
+            seen.extend(transactions.iter().map(|transaction| transaction.id));
+            let Some(next) = next else { break };
+            page.before = Some(next);
+        }
+        assert_eq!(seen, [early.id, late.id]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL and TEST_REDIS_URL"]
+    async fn failed_commit_caches_nothing() {
+        let server_data = server_data(LockBackend::Postgres).await;
+        let repo = TransactionRepository {
+            conn: server_data.pg_pool.clone(),
+        };
+        let cache = AccountCache {
+            re_conn: server_data.re_conn.clone(),
+        };
+        // checked on commit only, after the write went through
+        server_data
+            .pg_pool
+            .get()
+            .await
+            .unwrap()
+            .batch_execute(
+                r#"
+CREATE OR REPLACE FUNCTION fail_commit() RETURNS trigger LANGUAGE plpgsql
+AS $$ BEGIN RAISE EXCEPTION 'commit refused'; END $$;
+DROP TRIGGER IF EXISTS fail_commit ON transaction;
+CREATE CONSTRAINT TRIGGER fail_commit AFTER INSERT ON transaction
+DEFERRABLE INITIALLY DEFERRED FOR EACH ROW
+WHEN (NEW.description = 'nocommit') EXECUTE FUNCTION fail_commit();"#,
+            )
+            .await
+            .unwrap();
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+        cache.seed_account(acc.id, &acc, &[]).await.unwrap();
+
+        let service = BankAccountService::new(&server_data);
+        let command = || AccountCommands::HandleMoney {
+            account: acc.id,
+            transaction: Transaction::generate(50, "nocommit"),
+            idempotency_key: Some("retried".into()),
+        };
+        for _ in 0..2 {
+            let err = service.handler(command()).await.unwrap_err();
+            assert_eq!(err.status_code(), StatusCode::ServiceUnavailable);
+        }
+
+        assert!(cache
+            .get_outcome(acc.id, "retried")
+            .await
+            .unwrap()
+            .is_none());
+        let (cached, transactions, _) = cache.get_account(acc.id, 1).await.unwrap().unwrap();
+        assert_eq!(cached.balance.cents(), 0);
+        assert!(transactions.is_empty());
+        let stored = repo.get_account(acc.id).await.unwrap().unwrap();
+        assert_eq!(stored.balance.cents(), 0);
+    }
 }
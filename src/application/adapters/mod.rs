@@ -1,9 +1,17 @@
+pub mod validation;
+
+use crate::application::adapters::validation::{
+    account_id, positive_i64, string, Fields, PayloadError,
+};
+use crate::application::StatementCursor;
 use crate::domain::account::Account;
-use crate::domain::transaction::{Transaction, TransactionDescription, TransactionKind};
+use crate::domain::money::Money;
+use crate::domain::transaction::{
+    DescriptionRules, Transaction, TransactionDescription, TransactionId, TransactionKind,
+};
 use compact_str::{CompactString, ToCompactString};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::num::NonZeroI32;
 use time::format_description::well_known::Iso8601;
 use time::OffsetDateTime;
 
@@ -14,18 +22,78 @@ pub struct StatementDTO {
     saldo: SaldoDTO,
     #[serde(rename = "ultimas_transacoes")]
     transactions: Vec<TransactionDTO<'static>>,
+    /// Pass as `before` to get the next page, absent on the last one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<CompactString>,
 }
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Serialize)]
 pub struct SaldoDTO {
     #[serde(rename = "total")]
-    total_balance: i32,
+    total_balance: Money,
     #[serde(rename = "data_extrato")]
     data_extrato: CompactString,
     #[serde(rename = "limite")]
-    credit_limit: u32,
+    credit_limit: Money,
+}
+/// Response to a transaction, the account as it is afterwards.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BalanceDTO {
+    #[serde(rename = "limite")]
+    credit_limit: Money,
+    #[serde(rename = "saldo")]
+    balance: Money,
+    /// Id of the transaction that was just saved.
+    #[serde(rename = "transacao")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction: Option<TransactionId>,
+}
+
+impl From<Account> for BalanceDTO {
+    fn from(value: Account) -> Self {
+        Self {
+            credit_limit: value.credit_limit,
+            balance: value.balance,
+            transaction: None,
+        }
+    }
+}
+
+impl BalanceDTO {
+    pub fn with_transaction(mut self, transaction: Option<TransactionId>) -> Self {
+        self.transaction = transaction;
+        self
+    }
+}
+
+/// An account on its own, as opened or read through `/clientes`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct AccountDTO {
+    id: i32,
+    #[serde(rename = "limite")]
+    credit_limit: Money,
+    #[serde(rename = "saldo")]
+    balance: Money,
+}
+
+impl From<Account> for AccountDTO {
+    fn from(value: Account) -> Self {
+        Self {
+            id: value.id,
+            credit_limit: value.credit_limit,
+            balance: value.balance,
+        }
+    }
 }
+
+/// Body of requests opening an account or changing its limit.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct CreditLimitDTO {
+    #[serde(rename = "limite")]
+    pub credit_limit: i64,
+}
+
 impl StatementDTO {
     pub fn from_other(value: (Account, impl Iterator<Item = Transaction>)) -> Self {
         let formatted = OffsetDateTime::now_utc()
@@ -45,52 +113,74 @@ impl StatementDTO {
                 .into_iter()
                 .map(TransactionDTO::from)
                 .collect::<Vec<_>>(),
+            next_cursor: None,
         }
     }
+
+    pub fn with_next_cursor(mut self, cursor: Option<StatementCursor>) -> Self {
+        self.next_cursor = cursor.map(|cursor| cursor.to_compact_string());
+        self
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Serialize)]
 pub struct TransactionDTO<'a> {
+    pub id: TransactionId,
     #[serde(rename = "valor")]
-    pub amount: i32,
+    pub amount: Money,
     #[serde(rename = "tipo")]
-    #[serde(borrow)]
     pub kind: &'a str,
     #[serde(rename = "descricao")]
-    #[serde(borrow)]
     pub description: Cow<'a, str>,
     #[serde(rename = "realizada_em")]
-    #[serde(borrow)]
     pub created_on: Option<Cow<'a, str>>,
+    /// Links both legs of a transfer.
+    #[serde(rename = "transferencia")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_id: Option<i64>,
+    /// Transaction this one reverses.
+    #[serde(rename = "estorno_de")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reverses: Option<TransactionId>,
 }
 
-impl TryFrom<TransactionDTO<'_>> for Transaction {
-    type Error = ();
-
-    fn try_from(value: TransactionDTO) -> Result<Self, Self::Error> {
-        let kind = match value.kind {
-            "d" => TransactionKind::Debit,
-            "c" => TransactionKind::Credit,
-            _ => return Err(()),
-        };
+/// Strictly validates a transaction body, reporting every field that's wrong.
+pub fn transaction_from_json(
+    body: &[u8],
+    rules: &DescriptionRules,
+) -> Result<Transaction, PayloadError> {
+    let mut fields = Fields::parse(body)?;
 
-        let description = TransactionDescription::new(&value.description).map_err(|_| {
-            eprintln!("desc error");
-        })?;
+    let amount = fields.take("valor", positive_i64);
+    let kind = fields.take("tipo", |value| match value.as_str() {
+        Some("c") => Ok(TransactionKind::Credit),
+        Some("d") => Ok(TransactionKind::Debit),
+        _ => Err(r#"Must be "c" or "d"."#),
+    });
+    let description = fields.take("descricao", |value| {
+        TransactionDescription::with_rules(string(value)?, rules)
+            .map_err(|_| "Invalid description.")
+    });
+    fields.finish()?;
 
-        let amount = if matches!(kind, TransactionKind::Debit) {
-            NonZeroI32::new(-value.amount).ok_or(())?
-        } else {
-            NonZeroI32::new(value.amount).ok_or(())?
-        };
+    let (Some(amount), Some(kind), Some(description)) = (amount, kind, description) else {
+        unreachable!("missing fields are errors");
+    };
+    let amount = Money::from_cents(amount);
 
-        Ok(Self {
-            valor: amount,
-            tipo: kind,
-            descricao: description,
-            realizada_em: OffsetDateTime::now_utc(),
-        })
-    }
+    Ok(Transaction {
+        id: TransactionId::now(),
+        valor: match kind {
+            // positive amounts always have a negative counterpart
+            TransactionKind::Debit => amount.checked_neg().expect("No reason to fail."),
+            TransactionKind::Credit => amount,
+        },
+        tipo: kind,
+        descricao: description,
+        realizada_em: OffsetDateTime::now_utc(),
+        transferencia: None,
+        estorno_de: None,
+    })
 }
 
 impl From<Transaction> for TransactionDTO<'_> {
@@ -101,14 +191,153 @@ impl From<Transaction> for TransactionDTO<'_> {
         };
 
         let description = value.descricao.0.into();
-        let amount = i32::from(value.valor.abs());
+        let amount = value.valor.checked_abs().unwrap_or(Money::MAX);
         let created_on = Some(value.realizada_em.format(&Iso8601::DEFAULT).unwrap().into());
 
         Self {
+            id: value.id,
             amount,
             kind,
             description,
             created_on,
+            transfer_id: value.transferencia,
+            reverses: value.estorno_de,
+        }
+    }
+}
+
+/// Validated body of `POST /transferencias`.
+#[derive(Debug)]
+pub struct TransferDTO {
+    pub from: i32,
+    pub to: i32,
+    pub amount: Money,
+    pub description: TransactionDescription,
+}
+
+/// Strictly validates a transfer body, reporting every field that's wrong like
+/// [transaction_from_json] does.
+pub fn transfer_from_json(
+    body: &[u8],
+    rules: &DescriptionRules,
+) -> Result<TransferDTO, PayloadError> {
+    let mut fields = Fields::parse(body)?;
+
+    let from = fields.take("de", account_id);
+    let to = fields.take("para", |value| {
+        let to = account_id(value)?;
+        match from {
+            Some(from) if from == to => Err("Must be another account."),
+            _ => Ok(to),
+        }
+    });
+    let amount = fields.take("valor", positive_i64);
+    let description = fields.take("descricao", |value| {
+        TransactionDescription::with_rules(string(value)?, rules)
+            .map_err(|_| "Invalid description.")
+    });
+    fields.finish()?;
+
+    let (Some(from), Some(to), Some(amount), Some(description)) = (from, to, amount, description)
+    else {
+        unreachable!("missing fields are errors");
+    };
+
+    Ok(TransferDTO {
+        from,
+        to,
+        amount: Money::from_cents(amount),
+        description,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field_errors<T: std::fmt::Debug>(
+        result: Result<T, PayloadError>,
+    ) -> Vec<(String, &'static str)> {
+        match result.unwrap_err() {
+            PayloadError::Invalid(errors) => errors
+                .into_iter()
+                .map(|err| (err.field.to_string(), err.error))
+                .collect(),
+            err => panic!("{err:?}"),
         }
     }
+
+    fn transaction(body: &str) -> Result<Transaction, PayloadError> {
+        transaction_from_json(body.as_bytes(), &DescriptionRules::default())
+    }
+
+    fn transfer(body: &str) -> Result<TransferDTO, PayloadError> {
+        transfer_from_json(body.as_bytes(), &DescriptionRules::default())
+    }
+
+    #[test]
+    fn debits_are_negated() {
+        let debit = transaction(r#"{"valor": 10, "tipo": "d", "descricao": "x"}"#).unwrap();
+        assert_eq!(debit.valor.cents(), -10);
+        assert!(matches!(debit.tipo, TransactionKind::Debit));
+
+        let credit = transaction(r#"{"valor": 10, "tipo": "c", "descricao": "x"}"#).unwrap();
+        assert_eq!(credit.valor.cents(), 10);
+        assert!(matches!(credit.tipo, TransactionKind::Credit));
+    }
+
+    #[test]
+    fn transaction_field_errors() {
+        let found = field_errors(transaction(
+            r#"{"valor": 10, "tipo": "x", "descricao": null, "extra": 1}"#,
+        ));
+        assert_eq!(
+            found,
+            [
+                ("tipo".into(), r#"Must be "c" or "d"."#),
+                ("descricao".into(), "Required."),
+                ("extra".into(), "Unknown field."),
+            ]
+        );
+
+        let found = field_errors(transaction(r#"{"valor": 0, "tipo": "c", "descricao": ""}"#));
+        assert_eq!(
+            found,
+            [
+                ("valor".into(), "Must be a positive integer."),
+                ("descricao".into(), "Invalid description."),
+            ]
+        );
+    }
+
+    #[test]
+    fn transfer_fields() {
+        let valid = transfer(r#"{"de": 1, "para": 2, "valor": 10, "descricao": "rent"}"#).unwrap();
+        assert_eq!((valid.from, valid.to, valid.amount.cents()), (1, 2, 10));
+
+        let found = field_errors(transfer(
+            r#"{"de": 1, "para": 1, "valor": -10, "descricao": 5, "x": 0}"#,
+        ));
+        assert_eq!(
+            found,
+            [
+                ("para".into(), "Must be another account."),
+                ("valor".into(), "Must be a positive integer."),
+                ("descricao".into(), "Must be a string."),
+                ("x".into(), "Unknown field."),
+            ]
+        );
+
+        let found = field_errors(transfer(
+            r#"{"de": 2147483648, "para": "2", "valor": 1, "descricao": "x"}"#,
+        ));
+        assert_eq!(
+            found,
+            [
+                ("de".into(), "Must be an account id."),
+                ("para".into(), "Must be an account id."),
+            ]
+        );
+        assert_eq!(transfer("{").unwrap_err(), PayloadError::Malformed);
+    }
 }
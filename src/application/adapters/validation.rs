@@ -0,0 +1,156 @@
+//! Field by field validation of JSON bodies, so every mistake is reported at once instead of
+//! serde stopping at the first one.
+
+use compact_str::CompactString;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// A field of a request body that didn't validate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    #[serde(rename = "campo")]
+    pub field: CompactString,
+    #[serde(rename = "erro")]
+    pub error: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayloadError {
+    /// Not JSON at all.
+    Malformed,
+    Invalid(Vec<FieldError>),
+}
+
+/// Fields of a JSON object, taken one at a time.
+#[derive(Debug)]
+pub struct Fields {
+    map: Map<String, Value>,
+    errors: Vec<FieldError>,
+}
+
+impl Fields {
+    pub fn parse(body: &[u8]) -> Result<Self, PayloadError> {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Object(map)) => Ok(Self {
+                map,
+                errors: Vec::new(),
+            }),
+            Ok(_) => Err(PayloadError::Invalid(vec![FieldError {
+                field: CompactString::default(),
+                error: "Must be an object.",
+            }])),
+            Err(_) => Err(PayloadError::Malformed),
+        }
+    }
+
+    /// Validates the field with `validate`, `None` when it's missing, null or invalid.
+    pub fn take<T>(
+        &mut self,
+        name: &'static str,
+        validate: impl FnOnce(&Value) -> Result<T, &'static str>,
+    ) -> Option<T> {
+        let result = match self.map.remove(name) {
+            None | Some(Value::Null) => Err("Required."),
+            Some(value) => validate(&value),
+        };
+
+        result
+            .map_err(|error| {
+                self.errors.push(FieldError {
+                    field: name.into(),
+                    error,
+                })
+            })
+            .ok()
+    }
+
+    /// Fails with every error found, fields nobody took included.
+    pub fn finish(mut self) -> Result<(), PayloadError> {
+        self.errors.extend(self.map.keys().map(|field| FieldError {
+            field: field.into(),
+            error: "Unknown field.",
+        }));
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PayloadError::Invalid(self.errors))
+        }
+    }
+}
+
+/// Integers from 1 to `i64::MAX`, no fractions, no numbers in strings.
+pub fn positive_i64(value: &Value) -> Result<i64, &'static str> {
+    value
+        .as_i64()
+        .filter(|value| *value > 0)
+        .ok_or("Must be a positive integer.")
+}
+
+/// Integers from 1 to `i32::MAX`, as account ids are.
+pub fn account_id(value: &Value) -> Result<i32, &'static str> {
+    value
+        .as_i64()
+        .and_then(|value| i32::try_from(value).ok())
+        .filter(|value| *value > 0)
+        .ok_or("Must be an account id.")
+}
+
+pub fn string(value: &Value) -> Result<&str, &'static str> {
+    value.as_str().ok_or("Must be a string.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(body: &str) -> Vec<(String, &'static str)> {
+        let mut fields = Fields::parse(body.as_bytes()).unwrap();
+        fields.take("amount", positive_i64);
+        fields.take("name", |value| string(value).map(str::len));
+        match fields.finish() {
+            Ok(()) => Vec::new(),
+            Err(PayloadError::Invalid(errors)) => errors
+                .into_iter()
+                .map(|err| (err.field.to_string(), err.error))
+                .collect(),
+            Err(err) => panic!("{err:?}"),
+        }
+    }
+
+    #[test]
+    fn valid_fields() {
+        assert!(errors(r#"{"amount": 9223372036854775807, "name": "x"}"#).is_empty());
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let found = errors(r#"{"amount": 1.5, "other": true}"#);
+        assert_eq!(
+            found,
+            [
+                ("amount".into(), "Must be a positive integer."),
+                ("name".into(), "Required."),
+                ("other".into(), "Unknown field."),
+            ]
+        );
+    }
+
+    #[test]
+    fn amounts_are_positive_integers() {
+        for amount in ["0", "-1", "9223372036854775808", "\"10\"", "1e3", "null"] {
+            let found = errors(&format!(r#"{{"amount": {amount}, "name": "x"}}"#));
+            assert_eq!(found.len(), 1, "{amount}");
+            assert_eq!(found[0].0, "amount");
+        }
+    }
+
+    #[test]
+    fn not_an_object() {
+        assert_eq!(Fields::parse(b"{").unwrap_err(), PayloadError::Malformed);
+        assert!(matches!(
+            Fields::parse(b"[1]"),
+            Err(PayloadError::Invalid(_))
+        ));
+    }
+}
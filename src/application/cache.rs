@@ -1,80 +1,117 @@
 use crate::domain::account::Account;
-use crate::domain::transaction::Transaction;
+use crate::domain::errors::AccountError;
+use crate::domain::transaction::{Transaction, TransactionId};
+use crate::AnyResult;
 use compact_str::CompactString;
-use redis::streams::StreamRangeReply;
+use redis::streams::{StreamMaxlen, StreamRangeReply};
 use redis::{AsyncCommands, Value};
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
 
 pub struct AccountCache {
     pub re_conn: redis::aio::ConnectionManager,
 }
 
+impl Debug for AccountCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccountCache").finish_non_exhaustive()
+    }
+}
+
 impl AccountCache {
+    /// Transactions kept in each account's stream, older ones only live in Postgres.
+    pub const CACHED_TRANSACTIONS: usize = 100;
+
+    /// Prefixes every key, bumped whenever the shape of cached values changes. Values cached by
+    /// older releases are then left alone instead of failing to decode, startup seeds accounts
+    /// again under the new keys.
+    const VERSION: &'static str = "v2";
+
     const ACCOUNT_KEY: &'static str = "account";
     const TRANSACTIONS_KEY: &'static str = "transactions";
+    const IDEMPOTENCY_KEY: &'static str = "idempotency";
+    const SEEDED_KEY: &'static str = "seeded";
 
     fn key_trans_fn(user_id: i32) -> CompactString {
-        let key = Self::TRANSACTIONS_KEY;
-        compact_str::format_compact!("{key}:{user_id}")
+        let (version, key) = (Self::VERSION, Self::TRANSACTIONS_KEY);
+        compact_str::format_compact!("{version}:{key}:{user_id}")
     }
     fn key_acc_fn(user_id: i32) -> CompactString {
-        let key = Self::ACCOUNT_KEY;
-        compact_str::format_compact!("{key}:{user_id}")
+        let (version, key) = (Self::VERSION, Self::ACCOUNT_KEY);
+        compact_str::format_compact!("{version}:{key}:{user_id}")
+    }
+    fn key_seeded_fn(user_id: i32) -> CompactString {
+        let (version, key) = (Self::VERSION, Self::SEEDED_KEY);
+        compact_str::format_compact!("{version}:{key}:{user_id}")
+    }
+    fn key_idempotency_fn(user_id: i32, idempotency_key: &str) -> CompactString {
+        let (version, key) = (Self::VERSION, Self::IDEMPOTENCY_KEY);
+        compact_str::format_compact!("{version}:{key}:{user_id}:{idempotency_key}")
     }
 
+    /// Returns the account alongside up to `transactions` of its latest transactions, newest
+    /// first, or `None` when the account isn't cached.
+    ///
+    /// The flag tells whether the stream was seeded from Postgres, see
+    /// [AccountCache::seed_account]. Otherwise older transactions may be missing from it, e.g.
+    /// when Redis lost its data.
     pub async fn get_account(
         &self,
         user_id: i32,
-        with_transactions: bool,
-    ) -> (Account, Option<impl Iterator<Item = Transaction>>) {
+        transactions: usize,
+    ) -> AnyResult<Option<(Account, Vec<Transaction>, bool)>> {
         let trans_key = Self::key_trans_fn(user_id);
         let acc_key = Self::key_acc_fn(user_id);
+        let seeded_key = Self::key_seeded_fn(user_id);
 
-        if with_transactions {
+        if transactions > 0 {
             let mut pipe = redis::pipe();
             let mut conn = self.re_conn.clone();
-            let res: (StreamRangeReply, Vec<u8>) = pipe
-                .xrevrange_count(trans_key.as_str(), "+", "-", 10)
+            let res: (StreamRangeReply, Option<Vec<u8>>, bool) = pipe
+                .xrevrange_count(trans_key.as_str(), "+", "-", transactions)
                 .get(acc_key.as_str())
+                .exists(seeded_key.as_str())
                 .query_async(&mut conn)
-                .await
-                .unwrap();
+                .await?;
 
-            let acc = bitcode::deserialize::<Account>(&res.1).unwrap();
+            let Some(acc) = res.1 else {
+                return Ok(None);
+            };
+            let acc = bitcode::deserialize::<Account>(&acc)?;
             let transactions = res
                 .0
                 .ids
                 .into_iter()
-                .flat_map(|v| v.map.into_iter())
-                .filter_map(|(_, val)| {
-                    if let Value::Data(data) = val {
-                        let transaction = bitcode::deserialize::<Transaction>(&data).unwrap();
-                        Some(transaction)
-                    } else {
-                        None
-                    }
-                });
-
-            (acc, Some(transactions))
+                .flat_map(|v| v.map.into_values())
+                .filter_map(|val| match val {
+                    Value::Data(data) => Some(bitcode::deserialize::<Transaction>(&data)),
+                    _ => None,
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(Some((acc, transactions, res.2)))
         } else {
-            let acc = self
-                .re_conn
-                .clone()
-                .get(acc_key.as_str())
-                .await
-                .ok()
-                .and_then(|bytes: Vec<u8>| bitcode::deserialize::<Account>(&bytes).ok())
-                .unwrap();
-            (acc, None)
+            let acc: Option<Vec<u8>> = self.re_conn.clone().get(acc_key.as_str()).await?;
+            let Some(acc) = acc else {
+                return Ok(None);
+            };
+
+            Ok(Some((
+                bitcode::deserialize::<Account>(&acc)?,
+                Vec::new(),
+                false,
+            )))
         }
     }
 
+    /// Caches the account, and the transaction as the newest in its stream, keyed by its id.
     pub async fn save_account(
         &self,
         user_id: i32,
         acc: &Account,
         with_transaction: Option<&Transaction>,
-    ) {
-        let acc_serialized = bitcode::serialize(&acc).unwrap();
+    ) -> AnyResult<()> {
+        let acc_serialized = bitcode::serialize(&acc)?;
         let trans_key = Self::key_trans_fn(user_id);
         let acc_key = Self::key_acc_fn(user_id);
 
@@ -83,12 +120,104 @@ impl AccountCache {
         pipeline.set(acc_key.as_str(), acc_serialized);
 
         if let Some(trans) = with_transaction {
-            let trans_serialized = bitcode::serialize(&trans).unwrap();
+            let trans_serialized = bitcode::serialize(&trans)?;
+            pipeline
+                .xadd_maxlen(
+                    trans_key.as_str(),
+                    StreamMaxlen::Approx(Self::CACHED_TRANSACTIONS),
+                    "*",
+                    &[(trans.id.to_string(), trans_serialized)],
+                )
+                .ignore();
+        }
+
+        pipeline.query_async::<_, ()>(&mut like).await?;
+        Ok(())
+    }
+
+    /// Caches the account and replaces its stream with `transactions`, newest first and the latest
+    /// ones Postgres has. From then on the stream is known to hold the newest transactions without
+    /// gaps.
+    pub async fn seed_account(
+        &self,
+        user_id: i32,
+        acc: &Account,
+        transactions: &[Transaction],
+    ) -> AnyResult<()> {
+        let acc_serialized = bitcode::serialize(&acc)?;
+        let trans_key = Self::key_trans_fn(user_id);
+        let acc_key = Self::key_acc_fn(user_id);
+        let seeded_key = Self::key_seeded_fn(user_id);
+
+        let mut like = self.re_conn.clone();
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .set(acc_key.as_str(), acc_serialized)
+            .del(trans_key.as_str());
+
+        for trans in transactions.iter().take(Self::CACHED_TRANSACTIONS).rev() {
+            let trans_serialized = bitcode::serialize(&trans)?;
             pipeline
-                .xadd(trans_key.as_str(), "*", &[(user_id, trans_serialized)])
+                .xadd(
+                    trans_key.as_str(),
+                    "*",
+                    &[(trans.id.to_string(), trans_serialized)],
+                )
                 .ignore();
         }
+        pipeline.set(seeded_key.as_str(), 1);
+
+        pipeline.query_async::<_, ()>(&mut like).await?;
+        Ok(())
+    }
+
+    /// Forgets the account and its cached transactions.
+    pub async fn remove_account(&self, user_id: i32) -> AnyResult<()> {
+        let trans_key = Self::key_trans_fn(user_id);
+        let acc_key = Self::key_acc_fn(user_id);
+        let seeded_key = Self::key_seeded_fn(user_id);
+
+        self.re_conn
+            .clone()
+            .del::<_, ()>(&[acc_key.as_str(), trans_key.as_str(), seeded_key.as_str()])
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the outcome stored for an idempotency key alongside the
+    /// [fingerprint](Transaction::fingerprint) of the transaction it was first sent with, `None`
+    /// when it isn't cached.
+    pub async fn get_outcome(
+        &self,
+        user_id: i32,
+        key: &str,
+    ) -> AnyResult<Option<(i64, Result<(Account, Option<TransactionId>), AccountError>)>> {
+        let key = Self::key_idempotency_fn(user_id, key);
+        let outcome: Option<Vec<u8>> = self.re_conn.clone().get(key.as_str()).await?;
+
+        outcome
+            .map(|outcome| bitcode::deserialize(&outcome))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    /// Stores the outcome of an idempotency key, forgotten after `retention`.
+    pub async fn save_outcome(
+        &self,
+        user_id: i32,
+        key: &str,
+        fingerprint: i64,
+        outcome: &Result<(Account, Option<TransactionId>), AccountError>,
+        retention: Duration,
+    ) -> AnyResult<()> {
+        let key = Self::key_idempotency_fn(user_id, key);
+        let outcome = bitcode::serialize(&(fingerprint, outcome))?;
 
-        pipeline.query_async(&mut like).await.unwrap()
+        self.re_conn
+            .clone()
+            .set_ex::<_, _, ()>(key.as_str(), outcome, retention.as_secs().max(1))
+            .await?;
+        Ok(())
     }
 }
@@ -0,0 +1,163 @@
+//! Embedded schema migrations.
+//!
+//! Every migration runs once, in version order, and is recorded in `schema_migrations` with a
+//! checksum of its SQL. An applied migration whose file changed afterwards stops the startup.
+
+use crate::AnyResult;
+use deadpool_postgres::Pool;
+use eyre::bail;
+use std::hash::Hasher;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+impl Migration {
+    /// FNV-1a of the SQL, stable across builds and platforms.
+    pub fn checksum(&self) -> i64 {
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(self.sql.as_bytes());
+        hasher.finish() as i64
+    }
+}
+
+macro_rules! migration {
+    ($version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../../migrations/", $name, ".sql")),
+        }
+    };
+}
+
+/// All migrations, ordered by version.
+pub static MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_initial_schema"),
+    migration!(2, "0002_seed_accounts"),
+    migration!(3, "0003_idempotency_keys"),
+    migration!(4, "0004_account_id_identity"),
+    migration!(5, "0005_transfers"),
+    migration!(6, "0006_reversals"),
+    migration!(7, "0007_money_bigint"),
+    migration!(8, "0008_transaction_uuid"),
+    migration!(9, "0009_fencing_tokens"),
+    migration!(10, "0010_statement_keyset"),
+    migration!(11, "0011_idempotency_fingerprint"),
+    migration!(12, "0012_account_closed_on"),
+    migration!(13, "0013_overdraft_guard"),
+];
+
+/// Arbitrary key for the advisory lock serializing instances that start together.
+const MIGRATIONS_LOCK: i64 = 0x006d_6967_7261_7465;
+
+/// Applies the pending [MIGRATIONS], returns how many were applied.
+pub async fn run_migrations(pool: &Pool) -> AnyResult<usize> {
+    let mut conn = pool.get().await?;
+    let tx = conn.transaction().await?;
+
+    tx.execute("SELECT pg_advisory_xact_lock($1);", &[&MIGRATIONS_LOCK])
+        .await?;
+    tx.batch_execute(
+        r#"
+CREATE TABLE IF NOT EXISTS schema_migrations (
+	version bigint NOT NULL,
+	name text NOT NULL,
+	checksum bigint NOT NULL,
+	applied_on timestamp NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	CONSTRAINT schema_migrations_pk PRIMARY KEY (version)
+);"#,
+    )
+    .await?;
+
+    let applied = tx
+        .query(
+            "SELECT version, checksum FROM schema_migrations ORDER BY version;",
+            &[],
+        )
+        .await?
+        .into_iter()
+        .map(|r| (r.get::<usize, i64>(0), r.get::<usize, i64>(1)))
+        .collect::<Vec<_>>();
+
+    let pending = pending(MIGRATIONS, &applied)?;
+    for migration in pending {
+        tx.batch_execute(migration.sql).await?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3);",
+            &[&migration.version, &migration.name, &migration.checksum()],
+        )
+        .await?;
+        eprintln!("Applied migration {}", migration.name);
+    }
+
+    tx.commit().await?;
+    Ok(pending.len())
+}
+
+/// Checks the `(version, checksum)` pairs already applied against `migrations`, returns the ones
+/// still to apply.
+fn pending<'a>(migrations: &'a [Migration], applied: &[(i64, i64)]) -> AnyResult<&'a [Migration]> {
+    if migrations.windows(2).any(|w| w[0].version >= w[1].version) {
+        bail!("migrations aren't ordered by version");
+    }
+    if applied.len() > migrations.len() {
+        bail!("database has migrations this binary doesn't know about");
+    }
+
+    for (migration, &(version, checksum)) in migrations.iter().zip(applied) {
+        if migration.version != version {
+            bail!(
+                "applied migration {version} doesn't match {}",
+                migration.name
+            );
+        }
+        if migration.checksum() != checksum {
+            bail!("migration {} changed after being applied", migration.name);
+        }
+    }
+
+    Ok(&migrations[applied.len()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(migrations: &[Migration]) -> Vec<(i64, i64)> {
+        migrations
+            .iter()
+            .map(|m| (m.version, m.checksum()))
+            .collect()
+    }
+
+    #[test]
+    fn embedded_migrations_are_ordered() {
+        assert_eq!(pending(MIGRATIONS, &[]).unwrap().len(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn pending_skips_applied() {
+        let done = applied(&MIGRATIONS[..1]);
+        let todo = pending(MIGRATIONS, &done).unwrap();
+        assert_eq!(todo.len(), MIGRATIONS.len() - 1);
+        assert_eq!(todo[0].version, MIGRATIONS[1].version);
+
+        assert!(pending(MIGRATIONS, &applied(MIGRATIONS))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn pending_rejects_changed_migration() {
+        let mut done = applied(MIGRATIONS);
+        done[0].1 ^= 1;
+        assert!(pending(MIGRATIONS, &done).is_err());
+
+        let unknown = [(9999, 0)];
+        assert!(pending(MIGRATIONS, &unknown).is_err());
+    }
+}
@@ -1,11 +1,260 @@
 pub mod adapters;
 pub mod cache;
+pub mod migrations;
 pub mod repositories;
 
+use crate::domain::transaction::{DescriptionRules, Transaction, TransactionId};
+use crate::infrastructure::lock::{AccountLocks, LockBackend, RetryPolicy};
+use crate::AnyResult;
 use deadpool_postgres::Pool;
+use eyre::eyre;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use time::OffsetDateTime;
 
 #[derive(Clone)]
 pub struct ServerData {
     pub re_conn: redis::aio::ConnectionManager,
     pub pg_pool: Pool,
+    pub config: ServiceConfig,
+    pub locks: AccountLocks,
+}
+
+impl Debug for ServerData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerData")
+            .field("pg_pool", &self.pg_pool)
+            .field("config", &self.config)
+            .field("locks", &self.locks)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Knobs of the account service.
+#[derive(Debug, Copy, Clone)]
+pub struct ServiceConfig {
+    /// How long an `Idempotency-Key` keeps replaying the outcome of its first submission.
+    pub idempotency_retention: Duration,
+    pub description_rules: DescriptionRules,
+    /// How long an account lock is held at most, in case its holder never releases it.
+    pub lock_ttl: Duration,
+    /// How long to wait for an account lock someone else holds.
+    pub lock_retry: RetryPolicy,
+    pub lock_backend: LockBackend,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            idempotency_retention: Duration::from_secs(24 * 60 * 60),
+            description_rules: DescriptionRules::default(),
+            lock_ttl: Duration::from_millis(500),
+            lock_retry: RetryPolicy::default(),
+            lock_backend: LockBackend::default(),
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Defaults overridden by `IDEMPOTENCY_RETENTION_SECS`, `DESCRIPTION_MIN_LEN`,
+    /// `DESCRIPTION_MAX_LEN`, `LOCK_TTL_MS`, `LOCK_MAX_ATTEMPTS`, `LOCK_BASE_DELAY_MS`,
+    /// `LOCK_MAX_DELAY_MS`, `LOCK_DEADLINE_MS` and `LOCK_BACKEND`.
+    ///
+    /// Fails on description rules no description could satisfy.
+    pub fn from_env() -> AnyResult<Self> {
+        fn var<T: FromStr>(key: &str) -> Option<T> {
+            std::env::var(key).ok().and_then(|val| val.parse().ok())
+        }
+
+        let default = Self::default();
+        let min_len = var("DESCRIPTION_MIN_LEN").unwrap_or(default.description_rules.min_len);
+        let max_len = var("DESCRIPTION_MAX_LEN").unwrap_or(default.description_rules.max_len);
+        let description_rules = DescriptionRules::new(min_len, max_len)
+            .ok_or_else(|| eyre!("invalid description rules, {min_len} to {max_len} chars"))?;
+
+        Ok(Self {
+            idempotency_retention: var("IDEMPOTENCY_RETENTION_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.idempotency_retention),
+            description_rules,
+            lock_ttl: var("LOCK_TTL_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.lock_ttl),
+            lock_retry: RetryPolicy {
+                max_attempts: var("LOCK_MAX_ATTEMPTS").unwrap_or(default.lock_retry.max_attempts),
+                base_delay: var("LOCK_BASE_DELAY_MS")
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.lock_retry.base_delay),
+                max_delay: var("LOCK_MAX_DELAY_MS")
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.lock_retry.max_delay),
+                deadline: var("LOCK_DEADLINE_MS")
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.lock_retry.deadline),
+            },
+            lock_backend: var("LOCK_BACKEND").unwrap_or(default.lock_backend),
+        })
+    }
+}
+
+/// Which slice of an account's history a statement shows, newest first.
+#[derive(Debug, Copy, Clone)]
+pub struct StatementPage {
+    pub limit: usize,
+    /// Only transactions older than this cursor.
+    pub before: Option<StatementCursor>,
+    pub from: Option<OffsetDateTime>,
+    pub to: Option<OffsetDateTime>,
+}
+
+impl Default for StatementPage {
+    fn default() -> Self {
+        Self {
+            limit: Self::DEFAULT_LIMIT,
+            before: None,
+            from: None,
+            to: None,
+        }
+    }
+}
+
+impl StatementPage {
+    pub const DEFAULT_LIMIT: usize = 10;
+    pub const MAX_LIMIT: usize = 100;
+
+    pub fn contains(&self, transaction: &Transaction) -> bool {
+        let cursor = StatementCursor::of(transaction);
+        let created_on = transaction.realizada_em;
+
+        self.before.is_none_or(|before| cursor < before)
+            && self.from.is_none_or(|from| created_on >= from)
+            && self.to.is_none_or(|to| created_on <= to)
+    }
+}
+
+/// Position of a transaction in statements, ordered by creation time and then id, as transactions
+/// may share their creation time.
+///
+/// Sent as `<microseconds since the epoch>_<transaction id>`, microseconds being what Postgres
+/// keeps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StatementCursor {
+    created_on: i64,
+    id: TransactionId,
+}
+
+impl StatementCursor {
+    pub fn of(transaction: &Transaction) -> Self {
+        Self {
+            created_on: (transaction.realizada_em.unix_timestamp_nanos() / 1_000) as i64,
+            id: transaction.id,
+        }
+    }
+
+    pub fn created_on(&self) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp_nanos(i128::from(self.created_on) * 1_000)
+            .expect("checked when parsed")
+    }
+
+    pub fn id(&self) -> TransactionId {
+        self.id
+    }
+}
+
+impl Display for StatementCursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_{}", self.created_on, self.id)
+    }
+}
+
+impl FromStr for StatementCursor {
+    type Err = ();
+
+    /// Refuses times `OffsetDateTime` can't hold.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (created_on, id) = s.split_once('_').ok_or(())?;
+        let created_on = i64::from_str(created_on).map_err(|_| ())?;
+        OffsetDateTime::from_unix_timestamp_nanos(i128::from(created_on) * 1_000)
+            .map_err(|_| ())?;
+
+        Ok(Self {
+            created_on,
+            id: TransactionId::from_str(id).map_err(|_| ())?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Duration;
+
+    #[test]
+    fn page_filters() {
+        let transaction = Transaction::generate(-10, None);
+        let cursor = StatementCursor::of(&transaction);
+        let created_on = transaction.realizada_em;
+
+        assert!(StatementPage::default().contains(&transaction));
+
+        let page = StatementPage {
+            before: Some(cursor),
+            ..Default::default()
+        };
+        assert!(!page.contains(&transaction));
+
+        let later = Transaction {
+            realizada_em: created_on + Duration::microseconds(1),
+            ..Transaction::generate(-10, None)
+        };
+        let page = StatementPage {
+            before: Some(StatementCursor::of(&later)),
+            from: Some(created_on - Duration::seconds(1)),
+            to: Some(created_on),
+            ..Default::default()
+        };
+        assert!(page.contains(&transaction));
+
+        let page = StatementPage {
+            from: Some(created_on + Duration::seconds(1)),
+            ..Default::default()
+        };
+        assert!(!page.contains(&transaction));
+    }
+
+    #[test]
+    fn cursor_breaks_ties_by_id() {
+        let mut first = Transaction::generate(-10, None);
+        let mut second = Transaction {
+            realizada_em: first.realizada_em,
+            ..Transaction::generate(-10, None)
+        };
+        // ids made in the same millisecond are random
+        if second.id < first.id {
+            std::mem::swap(&mut first, &mut second);
+        }
+
+        let page = StatementPage {
+            before: Some(StatementCursor::of(&second)),
+            ..Default::default()
+        };
+        assert!(page.contains(&first));
+        assert!(!page.contains(&second));
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = StatementCursor::of(&Transaction::generate(-10, None));
+        assert_eq!(cursor.to_string().parse(), Ok(cursor));
+        assert_eq!(
+            cursor.created_on().unix_timestamp_nanos() / 1_000,
+            i128::from(cursor.created_on)
+        );
+
+        let id = cursor.id();
+        assert!(StatementCursor::from_str(&format!("9223372036854775807_{id}")).is_err());
+        assert!(StatementCursor::from_str("1700000000000000").is_err());
+        assert!(StatementCursor::from_str(&format!("x_{id}")).is_err());
+    }
 }
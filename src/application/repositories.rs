@@ -1,7 +1,18 @@
+use crate::application::StatementPage;
 use crate::domain::account::Account;
-use crate::domain::transaction::Transaction;
+use crate::domain::errors::{AccountError, ReversalError, TransactionError};
+use crate::domain::money::Money;
+use crate::domain::transaction::{
+    Transaction, TransactionDescription, TransactionId, TransactionKind,
+};
 use crate::AnyResult;
-use eyre::bail;
+use bytes::BytesMut;
+use deadpool_postgres::{GenericClient, Object};
+use eyre::{bail, eyre};
+use std::time::Duration;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use tokio_postgres::Row;
 
 #[derive(Debug)]
 pub struct TransactionRepository {
@@ -9,6 +20,7 @@ pub struct TransactionRepository {
 }
 
 impl TransactionRepository {
+    /// Open accounts, closed ones are left out.
     pub async fn get_accounts(&self) -> impl IntoIterator<Item = Account> {
         let conn = self.conn.get().await.unwrap();
 
@@ -16,40 +28,1282 @@ impl TransactionRepository {
 SELECT id
      , balance
      , credit_limit 
-  FROM account;"#;
+  FROM account
+ WHERE closed_on IS NULL;"#;
         let stmt = conn.prepare_cached(query).await.unwrap();
         let res = conn.query(&stmt, &[]).await.unwrap();
 
         res.into_iter().map(|r| Account {
             id: r.get(0),
             balance: r.get(1),
-            credit_limit: r.get::<usize, i32>(2) as u32,
+            credit_limit: r.get(2),
         })
     }
 
-    /// Returns the source-of-truth balance for user_id.
+    /// Opens an account with no balance.
+    pub async fn create_account(&self, credit_limit: Money) -> AnyResult<Account> {
+        let conn = self.conn.get().await?;
+
+        let stmt = conn
+            .prepare_cached("INSERT INTO account (credit_limit) VALUES ($1) RETURNING id;")
+            .await?;
+        let row = conn.query_one(&stmt, &[&credit_limit]).await?;
+
+        Ok(Account::new(row.get(0), credit_limit))
+    }
+
+    /// `None` for missing and closed accounts alike.
+    pub async fn get_account(&self, user_id: i32) -> AnyResult<Option<Account>> {
+        let conn = self.conn.get().await?;
+
+        let query = r#"
+SELECT balance
+     , credit_limit
+  FROM account
+ WHERE id = $1
+   AND closed_on IS NULL;"#;
+        let stmt = conn.prepare_cached(query).await?;
+        let row = conn.query_opt(&stmt, &[&user_id]).await?;
+
+        Ok(row.map(|r| Account {
+            id: user_id,
+            balance: r.get(0),
+            credit_limit: r.get(1),
+        }))
+    }
+
+    /// Changes the credit limit, unless the balance already goes beyond it or the account is
+    /// closed.
+    pub async fn set_credit_limit(
+        &self,
+        user_id: i32,
+        credit_limit: Money,
+    ) -> AnyResult<Result<Account, AccountError>> {
+        let conn = self.conn.get().await?;
+
+        let query = r#"
+  WITH target
+    AS (SELECT id, closed_on FROM account WHERE id = $1)
+     , updated
+    AS (UPDATE account
+           SET credit_limit = $2
+         WHERE id = $1
+           AND closed_on IS NULL
+           AND balance >= -$2::bigint
+     RETURNING id, balance)
+SELECT updated.balance
+     , target.closed_on IS NOT NULL
+  FROM target
+  LEFT JOIN updated ON updated.id = target.id;"#;
+        let stmt = conn.prepare_cached(query).await?;
+        let row = conn.query_opt(&stmt, &[&user_id, &credit_limit]).await?;
+
+        let Some(row) = row else {
+            return Ok(Err(AccountError::NotFound));
+        };
+        if row.get::<usize, bool>(1) {
+            return Ok(Err(AccountError::Closed));
+        }
+        let Some(balance) = row.get::<usize, Option<Money>>(0) else {
+            return Ok(Err(AccountError::LimitBelowBalance));
+        };
+
+        Ok(Ok(Account {
+            id: user_id,
+            balance,
+            credit_limit,
+        }))
+    }
+
+    /// Closes the account, which keeps its history but refuses any further writes. Only accounts
+    /// with a zeroed balance can be closed.
+    pub async fn close_account(&self, user_id: i32) -> AnyResult<Result<Account, AccountError>> {
+        let conn = self.conn.get().await?;
+
+        let query = r#"
+  WITH target
+    AS (SELECT id, closed_on FROM account WHERE id = $1)
+     , closed
+    AS (UPDATE account
+           SET closed_on = $2
+         WHERE id = $1
+           AND closed_on IS NULL
+           AND balance = 0
+     RETURNING id, credit_limit)
+SELECT closed.credit_limit
+     , target.closed_on IS NOT NULL
+  FROM target
+  LEFT JOIN closed ON closed.id = target.id;"#;
+        let stmt = conn.prepare_cached(query).await?;
+        let closed_on = to_timestamp(OffsetDateTime::now_utc());
+        let row = conn.query_opt(&stmt, &[&user_id, &closed_on]).await?;
+
+        let Some(row) = row else {
+            return Ok(Err(AccountError::NotFound));
+        };
+        if row.get::<usize, bool>(1) {
+            return Ok(Err(AccountError::Closed));
+        }
+        match row.get::<usize, Option<Money>>(0) {
+            None => Ok(Err(AccountError::NonZeroBalance)),
+            Some(credit_limit) => Ok(Ok(Account::new(user_id, credit_limit))),
+        }
+    }
+
+    /// Returns the source-of-truth [Account] for user_id after the transaction.
+    ///
+    /// The credit limit is checked by the UPDATE itself, which re-reads the locked row, so
+    /// concurrent debits can't overdraw the account. A rejected transaction isn't inserted.
+    ///
+    /// Writes made under an account lock pass its fencing token, and fail if the account already
+    /// saw a newer one: the lock expired and someone else took it. Under a Postgres lock they pass
+    /// its connection as `within` instead, and join the transaction holding the lock, which
+    /// commits when it's released. Otherwise they commit right away.
     pub async fn save_and_get_balance(
         &self,
         user_id: i32,
         transaction: &Transaction,
-    ) -> AnyResult<()> {
+        fencing_token: Option<i64>,
+        within: Option<&Object>,
+    ) -> AnyResult<Result<Account, AccountError>> {
+        if let Some(client) = within {
+            return apply_transaction(client, user_id, transaction, fencing_token).await;
+        }
+
+        let conn = self.conn.get().await?;
+        apply_transaction(&conn, user_id, transaction, fencing_token).await
+    }
+
+    /// Like [TransactionRepository::save_and_get_balance], but only the first submission under
+    /// `key` is handled, later ones replay its outcome until it's older than `retention`. A later
+    /// submission of another transaction is refused.
+    ///
+    /// The key is claimed in the same database transaction that handles the transaction, so
+    /// concurrent submissions wait for the first one to commit and then replay it.
+    pub async fn save_idempotent(
+        &self,
+        user_id: i32,
+        key: &str,
+        transaction: &Transaction,
+        fencing_token: Option<i64>,
+        retention: Duration,
+        within: Option<&Object>,
+    ) -> AnyResult<Result<Idempotent, TransactionError>> {
+        if let Some(client) = within {
+            return idempotent(client, user_id, key, transaction, fencing_token, retention).await;
+        }
+
+        let mut conn = self.conn.get().await?;
+        let tx = conn.transaction().await?;
+        let outcome = idempotent(&tx, user_id, key, transaction, fencing_token, retention).await?;
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    /// Forgets idempotency keys older than `retention`, returns how many.
+    pub async fn purge_idempotency_keys(&self, retention: Duration) -> AnyResult<u64> {
+        let conn = self.conn.get().await?;
+        let expired = to_timestamp(OffsetDateTime::now_utc() - retention);
+
+        let stmt = conn
+            .prepare_cached("DELETE FROM idempotency_key WHERE created_on < $1;")
+            .await?;
+        Ok(conn.execute(&stmt, &[&expired]).await?)
+    }
+
+    /// Moves money from one account to the other, returns both accounts afterwards. The legs get
+    /// the transfer id linking them.
+    ///
+    /// Both accounts are locked in id order, so transfers going opposite ways can't deadlock.
+    /// `fencing_tokens` are those of the `from` and `to` account locks, see
+    /// [TransactionRepository::save_and_get_balance].
+    pub async fn save_transfer(
+        &self,
+        from: i32,
+        to: i32,
+        debit: &mut Transaction,
+        credit: &mut Transaction,
+        fencing_tokens: (Option<i64>, Option<i64>),
+        within: Option<&Object>,
+    ) -> AnyResult<Result<(Account, Account), AccountError>> {
+        if let Some(client) = within {
+            return transfer(client, from, to, debit, credit, fencing_tokens).await;
+        }
+
+        let mut conn = self.conn.get().await?;
+        let tx = conn.transaction().await?;
+        let outcome = transfer(&tx, from, to, debit, credit, fencing_tokens).await?;
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    /// Reverses transaction_id, returns the account afterwards and the compensating
+    /// transaction.
+    ///
+    /// With `force` the reversal goes through even if it takes the balance past the credit limit.
+    /// `fencing_token` is that of the account lock, see
+    /// [TransactionRepository::save_and_get_balance].
+    pub async fn save_reversal(
+        &self,
+        user_id: i32,
+        transaction_id: TransactionId,
+        force: bool,
+        fencing_token: Option<i64>,
+        within: Option<&Object>,
+    ) -> AnyResult<Result<(Account, Transaction), ReversalError>> {
+        if let Some(client) = within {
+            return reversal(client, user_id, transaction_id, force, fencing_token).await;
+        }
+
+        let mut conn = self.conn.get().await?;
+        let tx = conn.transaction().await?;
+        let outcome = reversal(&tx, user_id, transaction_id, force, fencing_token).await?;
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    /// Returns one page of transactions for user_id, newest first.
+    ///
+    /// Fetches one transaction more than `page.limit`, so callers can tell whether there's a next
+    /// page.
+    pub async fn get_transactions(
+        &self,
+        user_id: i32,
+        page: &StatementPage,
+    ) -> AnyResult<Vec<Transaction>> {
         let conn = self.conn.get().await?;
 
         let query = r#"
-        WITH insertion
-              AS (INSERT INTO transaction (amount, description, account_id) VALUES ($1, $2, $3) RETURNING account_id, amount )
-   UPDATE account acc
-      SET balance = balance + insertion.amount
-     FROM insertion
-    WHERE acc.id = insertion.account_id;"#;
+SELECT amount
+     , kind
+     , description
+     , created_on
+     , transfer_id
+     , id
+     , reverses
+  FROM transaction
+ WHERE account_id = $1
+   AND ($2::timestamp IS NULL OR (created_on, id) < ($2, $6::uuid))
+   AND ($3::timestamp IS NULL OR created_on >= $3)
+   AND ($4::timestamp IS NULL OR created_on <= $4)
+ ORDER BY created_on DESC
+        , id DESC
+ LIMIT $5;"#;
         let stmt = conn.prepare_cached(query).await?;
 
-        let desc = transaction.descricao.0.as_str();
-        let amount = transaction.valor;
+        let before = page.before.map(|cursor| to_timestamp(cursor.created_on()));
+        let before_id = page.before.map(|cursor| cursor.id());
+        let from = page.from.map(to_timestamp);
+        let to = page.to.map(to_timestamp);
+        let limit = (page.limit + 1) as i64;
+
+        let rows = conn
+            .query(&stmt, &[&user_id, &before, &from, &to, &limit, &before_id])
+            .await?;
+
+        rows.iter().map(transaction_from_row).collect()
+    }
+
+    /// Returns the transaction, `None` unless it's one of user_id's.
+    pub async fn get_transaction(
+        &self,
+        user_id: i32,
+        transaction_id: TransactionId,
+    ) -> AnyResult<Option<Transaction>> {
+        let conn = self.conn.get().await?;
+
+        let query = r#"
+SELECT amount
+     , kind
+     , description
+     , created_on
+     , transfer_id
+     , id
+     , reverses
+  FROM transaction
+ WHERE id = $1
+   AND account_id = $2;"#;
+        let stmt = conn.prepare_cached(query).await?;
+        let row = conn.query_opt(&stmt, &[&transaction_id, &user_id]).await?;
+
+        row.as_ref().map(transaction_from_row).transpose()
+    }
+}
+
+/// Outcome of a transaction submitted under an idempotency key.
+#[derive(Debug, Copy, Clone)]
+pub enum Idempotent {
+    /// First submission, the transaction was just handled.
+    Applied(Result<(Account, Option<TransactionId>), AccountError>),
+    /// Outcome stored by an earlier submission, nothing was touched. The transaction id is the
+    /// one of the earlier submission, unknown to keys claimed before migration 0008.
+    Replayed(Result<(Account, Option<TransactionId>), AccountError>),
+}
+
+impl Idempotent {
+    pub fn outcome(self) -> Result<(Account, Option<TransactionId>), AccountError> {
+        match self {
+            Idempotent::Applied(outcome) | Idempotent::Replayed(outcome) => outcome,
+        }
+    }
+}
+
+/// Inserts the transaction and updates the balance, unless it'd go over the credit limit or the
+/// fencing token is stale.
+async fn apply_transaction(
+    client: &impl GenericClient,
+    user_id: i32,
+    transaction: &Transaction,
+    fencing_token: Option<i64>,
+) -> AnyResult<Result<Account, AccountError>> {
+    let query = r#"
+  WITH target
+    AS (SELECT id, balance, credit_limit, fencing_token, closed_on FROM account WHERE id = $3)
+     , updated
+    AS (UPDATE account
+           SET balance = balance + $1
+             , fencing_token = coalesce($7, fencing_token)
+         WHERE id = $3
+           AND closed_on IS NULL
+           AND fencing_token <= coalesce($7, fencing_token)
+           AND balance::numeric + $1::bigint BETWEEN -credit_limit AND 9223372036854775807
+     RETURNING id, balance)
+     , insertion
+    AS (INSERT INTO transaction (id, amount, kind, description, account_id, created_on)
+        SELECT $6, $1, $5, $2, id, $4 FROM updated)
+SELECT target.balance
+     , target.credit_limit
+     , updated.balance
+     , target.fencing_token
+     , target.closed_on IS NOT NULL
+  FROM target
+  LEFT JOIN updated ON updated.id = target.id;"#;
+    let stmt = client.prepare_cached(query).await?;
+
+    let desc = transaction.descricao.0.as_str();
+    let amount = transaction.valor;
+
+    let created_on = to_timestamp(transaction.realizada_em);
+    let kind = kind_code(transaction.tipo);
+
+    let row = client
+        .query_opt(
+            &stmt,
+            &[
+                &amount,
+                &desc,
+                &user_id,
+                &created_on,
+                &kind,
+                &transaction.id,
+                &fencing_token,
+            ],
+        )
+        .await?;
+    let Some(row) = row else {
+        return Ok(Err(AccountError::NotFound));
+    };
+    let acc = Account {
+        id: user_id,
+        balance: row.get(0),
+        credit_limit: row.get(1),
+    };
+    let Some(balance) = row.get::<usize, Option<Money>>(2) else {
+        if row.get::<usize, bool>(4) {
+            return Ok(Err(AccountError::Closed));
+        }
+        check_fencing_token(user_id, fencing_token, row.get(3))?;
+        // the account may have changed while waiting for its lock, a transaction the snapshot
+        // allows was still refused for credit
+        let err = acc
+            .add_transaction(transaction)
+            .err()
+            .unwrap_or(AccountError::InsufficientCredit);
+        return Ok(Err(err));
+    };
+
+    Ok(Ok(Account { balance, ..acc }))
+}
+
+/// See [TransactionRepository::save_idempotent], `tx` being in a transaction.
+async fn idempotent(
+    tx: &impl GenericClient,
+    user_id: i32,
+    key: &str,
+    transaction: &Transaction,
+    fencing_token: Option<i64>,
+    retention: Duration,
+) -> AnyResult<Result<Idempotent, TransactionError>> {
+    let now = OffsetDateTime::now_utc();
+    let created_on = to_timestamp(now);
+    let expired = to_timestamp(now - retention);
+    let fingerprint = transaction.fingerprint();
+
+    let stmt = tx
+        .prepare_cached(
+            r#"
+DELETE FROM idempotency_key
+ WHERE account_id = $1
+   AND key = $2
+   AND created_on < $3;"#,
+        )
+        .await?;
+    tx.execute(&stmt, &[&user_id, &key, &expired]).await?;
+
+    let stmt = tx
+        .prepare_cached(
+            r#"
+INSERT INTO idempotency_key (account_id, key, created_on, fingerprint)
+VALUES ($1, $2, $3, $4)
+ON CONFLICT DO NOTHING;"#,
+        )
+        .await?;
+    let claimed = tx
+        .execute(&stmt, &[&user_id, &key, &created_on, &fingerprint])
+        .await?
+        == 1;
+
+    if !claimed {
+        let stmt = tx
+            .prepare_cached(
+                r#"
+SELECT balance
+ , credit_limit
+ , rejection
+ , transaction_id
+ , fingerprint
+  FROM idempotency_key
+ WHERE account_id = $1
+   AND key = $2;"#,
+            )
+            .await?;
+        let row = tx
+            .query_opt(&stmt, &[&user_id, &key])
+            .await?
+            .ok_or_else(|| eyre!("idempotency key {key:?} vanished"))?;
+
+        // keys claimed before fingerprints were stored replay for anything
+        if row
+            .get::<usize, Option<i64>>(4)
+            .is_some_and(|first| first != fingerprint)
+        {
+            return Ok(Err(TransactionError::IdempotencyKeyReused));
+        }
+
+        let outcome = match (
+            row.get::<usize, Option<Money>>(0),
+            row.get::<usize, Option<Money>>(1),
+            row.get::<usize, Option<&str>>(2),
+            row.get::<usize, Option<TransactionId>>(3),
+        ) {
+            // keys claimed before migration 0008 don't know their transaction
+            (Some(balance), Some(credit_limit), None, transaction_id) => {
+                let acc = Account {
+                    id: user_id,
+                    balance,
+                    credit_limit,
+                };
+                Ok((acc, transaction_id))
+            }
+            (None, None, Some(rejection), None) => Err(from_rejection_code(rejection)?),
+            _ => bail!("idempotency key {key:?} has no outcome"),
+        };
+        return Ok(Ok(Idempotent::Replayed(outcome)));
+    }
+
+    let outcome = apply_transaction(tx, user_id, transaction, fencing_token)
+        .await?
+        .map(|acc| (acc, Some(transaction.id)));
+
+    let (balance, credit_limit, rejection, transaction_id) = match outcome {
+        Ok((acc, id)) => (Some(acc.balance), Some(acc.credit_limit), None, id),
+        Err(err) => (None, None, Some(rejection_code(err)), None),
+    };
+    let stmt = tx
+        .prepare_cached(
+            r#"
+UPDATE idempotency_key
+   SET balance = $3
+ , credit_limit = $4
+ , rejection = $5
+ , transaction_id = $6
+ WHERE account_id = $1
+   AND key = $2;"#,
+        )
+        .await?;
+    tx.execute(
+        &stmt,
+        &[
+            &user_id,
+            &key,
+            &balance,
+            &credit_limit,
+            &rejection,
+            &transaction_id,
+        ],
+    )
+    .await?;
+
+    Ok(Ok(Idempotent::Applied(outcome)))
+}
+
+/// See [TransactionRepository::save_transfer], `tx` being in a transaction.
+async fn transfer(
+    tx: &impl GenericClient,
+    from: i32,
+    to: i32,
+    debit: &mut Transaction,
+    credit: &mut Transaction,
+    fencing_tokens: (Option<i64>, Option<i64>),
+) -> AnyResult<Result<(Account, Account), AccountError>> {
+    let query = r#"
+SELECT id
+ , balance
+ , credit_limit
+ , closed_on IS NOT NULL
+ , fencing_token
+  FROM account
+ WHERE id = ANY($1)
+ ORDER BY id
+   FOR UPDATE;"#;
+    let stmt = tx.prepare_cached(query).await?;
+    let rows = tx.query(&stmt, &[&[from, to].as_slice()]).await?;
+    for row in &rows {
+        let id = row.get::<usize, i32>(0);
+        let token = if id == from {
+            fencing_tokens.0
+        } else {
+            fencing_tokens.1
+        };
+        check_fencing_token(id, token, row.get(4))?;
+    }
+    if rows.iter().any(|r| r.get::<usize, bool>(3)) {
+        return Ok(Err(AccountError::Closed));
+    }
+    let accounts = rows
+        .into_iter()
+        .map(|r| Account {
+            id: r.get(0),
+            balance: r.get(1),
+            credit_limit: r.get(2),
+        })
+        .collect::<Vec<_>>();
+
+    let find = |id: i32| accounts.iter().find(|acc| acc.id == id).copied();
+    let (Some(from_acc), Some(to_acc)) = (find(from), find(to)) else {
+        return Ok(Err(AccountError::NotFound));
+    };
+    let from_acc = match from_acc.add_transaction(debit) {
+        Ok(acc) => acc,
+        Err(err) => return Ok(Err(err)),
+    };
+    let to_acc = match to_acc.add_transaction(credit) {
+        Ok(acc) => acc,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    let stmt = tx.prepare_cached(UPDATE_FENCED_BALANCE).await?;
+    for (acc, token) in [(&from_acc, fencing_tokens.0), (&to_acc, fencing_tokens.1)] {
+        tx.execute(&stmt, &[&acc.id, &acc.balance, &token]).await?;
+    }
+
+    let query = r#"
+  WITH transfer
+AS (SELECT nextval('transfer_id_seq') AS id)
+INSERT INTO transaction (id, amount, kind, description, account_id, created_on, transfer_id)
+SELECT legs.*, transfer.id
+  FROM (VALUES ($9::uuid, $1::bigint, $2::char(1), $3::text, $4::integer, $5::timestamp)
+         , ($10, $6, $7, $3, $8, $5)) AS legs
+ , transfer
+RETURNING transfer_id;"#;
+    let stmt = tx.prepare_cached(query).await?;
+    let rows = tx
+        .query(
+            &stmt,
+            &[
+                &debit.valor,
+                &kind_code(debit.tipo),
+                &debit.descricao.0.as_str(),
+                &from,
+                &to_timestamp(debit.realizada_em),
+                &credit.valor,
+                &kind_code(credit.tipo),
+                &to,
+                &debit.id,
+                &credit.id,
+            ],
+        )
+        .await?;
+    let transfer_id = rows.first().map(|row| row.get(0));
+    debit.transferencia = transfer_id;
+    credit.transferencia = transfer_id;
+
+    Ok(Ok((from_acc, to_acc)))
+}
+
+/// See [TransactionRepository::save_reversal], `tx` being in a transaction.
+async fn reversal(
+    tx: &impl GenericClient,
+    user_id: i32,
+    transaction_id: TransactionId,
+    force: bool,
+    fencing_token: Option<i64>,
+) -> AnyResult<Result<(Account, Transaction), ReversalError>> {
+    // the account lock serializes reversals of its transactions
+    let query = r#"
+SELECT balance
+ , credit_limit
+ , closed_on IS NOT NULL
+ , fencing_token
+  FROM account
+ WHERE id = $1
+   FOR UPDATE;"#;
+    let stmt = tx.prepare_cached(query).await?;
+    let Some(row) = tx.query_opt(&stmt, &[&user_id]).await? else {
+        return Ok(Err(AccountError::NotFound.into()));
+    };
+    check_fencing_token(user_id, fencing_token, row.get(3))?;
+    if row.get::<usize, bool>(2) {
+        return Ok(Err(AccountError::Closed.into()));
+    }
+    let acc = Account {
+        id: user_id,
+        balance: row.get(0),
+        credit_limit: row.get(1),
+    };
+
+    let query = r#"
+SELECT amount
+ , kind
+ , description
+ , created_on
+ , transfer_id
+ , id
+ , reverses
+ , EXISTS (SELECT 1 FROM transaction reversal WHERE reversal.reverses = original.id)
+  FROM transaction original
+ WHERE id = $1
+   AND account_id = $2;"#;
+    let stmt = tx.prepare_cached(query).await?;
+    let Some(row) = tx.query_opt(&stmt, &[&transaction_id, &user_id]).await? else {
+        return Ok(Err(TransactionError::NotFound.into()));
+    };
+    if row.get::<usize, bool>(7) {
+        return Ok(Err(TransactionError::AlreadyReversed.into()));
+    }
+
+    let original = transaction_from_row(&row)?;
+    let reversal = match original.reversal() {
+        Ok(reversal) => reversal,
+        Err(err) => return Ok(Err(err.into())),
+    };
+    let acc = if force {
+        // the database refuses balances past the limit unless told otherwise
+        tx.execute(
+            "SELECT set_config('rinha.allow_overdraft', 'on', true);",
+            &[],
+        )
+        .await?;
+        acc.force_transaction(&reversal)
+    } else {
+        acc.add_transaction(&reversal)
+    };
+    let acc = match acc {
+        Ok(acc) => acc,
+        Err(err) => return Ok(Err(err.into())),
+    };
+
+    let stmt = tx.prepare_cached(UPDATE_FENCED_BALANCE).await?;
+    tx.execute(&stmt, &[&user_id, &acc.balance, &fencing_token])
+        .await?;
+
+    let query = r#"
+INSERT INTO transaction (id, amount, kind, description, account_id, created_on, reverses)
+VALUES ($7, $1, $2, $3, $4, $5, $6);"#;
+    let stmt = tx.prepare_cached(query).await?;
+    tx.execute(
+        &stmt,
+        &[
+            &reversal.valor,
+            &kind_code(reversal.tipo),
+            &reversal.descricao.0.as_str(),
+            &user_id,
+            &to_timestamp(reversal.realizada_em),
+            &transaction_id,
+            &reversal.id,
+        ],
+    )
+    .await?;
+
+    Ok(Ok((acc, reversal)))
+}
+
+/// Sets the balance of an account locked `FOR UPDATE` and advances its fencing token, once
+/// [check_fencing_token] passed.
+const UPDATE_FENCED_BALANCE: &str = r#"
+UPDATE account
+   SET balance = $2
+     , fencing_token = coalesce($3, fencing_token)
+ WHERE id = $1;"#;
+
+/// Fails if the account already saw a newer fencing token than `fencing_token`: the lock the
+/// write was made under expired and someone else took it.
+fn check_fencing_token(user_id: i32, fencing_token: Option<i64>, seen: i64) -> AnyResult<()> {
+    if let Some(token) = fencing_token.filter(|token| *token < seen) {
+        bail!("account {user_id} lock expired, fencing token {token} is behind {seen}");
+    }
+    Ok(())
+}
+
+/// `rejection` column value of [AccountError].
+fn rejection_code(err: AccountError) -> &'static str {
+    match err {
+        AccountError::InsufficientCredit => "insufficient_credit",
+        AccountError::NotFound => "not_found",
+        AccountError::LimitBelowBalance => "limit_below_balance",
+        AccountError::NonZeroBalance => "non_zero_balance",
+        AccountError::BalanceOverflow => "balance_overflow",
+        AccountError::Closed => "closed",
+    }
+}
+
+fn from_rejection_code(code: &str) -> AnyResult<AccountError> {
+    match code {
+        "insufficient_credit" => Ok(AccountError::InsufficientCredit),
+        "not_found" => Ok(AccountError::NotFound),
+        "limit_below_balance" => Ok(AccountError::LimitBelowBalance),
+        "non_zero_balance" => Ok(AccountError::NonZeroBalance),
+        "balance_overflow" => Ok(AccountError::BalanceOverflow),
+        "closed" => Ok(AccountError::Closed),
+        other => bail!("unknown rejection {other:?}"),
+    }
+}
+
+/// Amounts are `bigint` columns holding cents.
+impl<'a> FromSql<'a> for Money {
+    fn from_sql(
+        ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        i64::from_sql(ty, raw).map(Money::from_cents)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i64 as FromSql>::accepts(ty)
+    }
+}
+
+impl ToSql for Money {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        self.cents().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i64 as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+/// Transaction ids are `uuid` columns, sent as their 16 bytes.
+impl<'a> FromSql<'a> for TransactionId {
+    fn from_sql(
+        _ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let raw = <[u8; 16]>::try_from(raw)?;
+        Ok(TransactionId::from_u128(u128::from_be_bytes(raw)))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::UUID
+    }
+}
+
+impl ToSql for TransactionId {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        out.extend_from_slice(&self.as_u128().to_be_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::UUID
+    }
+
+    to_sql_checked!();
+}
+
+/// Reads a transaction selected as `amount, kind, description, created_on, transfer_id, id,
+/// reverses`.
+fn transaction_from_row(row: &Row) -> AnyResult<Transaction> {
+    Ok(Transaction {
+        id: row.get(5),
+        valor: row.get(0),
+        tipo: from_kind_code(row.get(1))?,
+        descricao: TransactionDescription(row.get::<usize, &str>(2).into()),
+        realizada_em: row.get::<usize, PrimitiveDateTime>(3).assume_utc(),
+        transferencia: row.get(4),
+        estorno_de: row.get(6),
+    })
+}
 
-        conn.query_one(&stmt, &[&i32::from(amount), &desc, &user_id])
+/// `created_on` is a `timestamp` column, always in UTC.
+fn to_timestamp(date: OffsetDateTime) -> PrimitiveDateTime {
+    let date = date.to_offset(UtcOffset::UTC);
+    PrimitiveDateTime::new(date.date(), date.time())
+}
+
+/// `kind` column value of [TransactionKind].
+fn kind_code(kind: TransactionKind) -> &'static str {
+    match kind {
+        TransactionKind::Credit => "c",
+        TransactionKind::Debit => "d",
+    }
+}
+
+fn from_kind_code(code: &str) -> AnyResult<TransactionKind> {
+    match code {
+        "c" => Ok(TransactionKind::Credit),
+        "d" => Ok(TransactionKind::Debit),
+        other => bail!("unknown transaction kind {other:?}"),
+    }
+}
+
+/// These need a Postgres they can migrate and write to, e.g.
+/// `TEST_DATABASE_URL=postgres://postgres@localhost/rinha_test cargo test -- --ignored`. Each test
+/// opens accounts of its own.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::migrations::run_migrations;
+    use crate::domain::transaction::DescriptionRules;
+    use crate::infrastructure::lock::{
+        AdvisoryLocks, DistributedLock, LockError, LockGuard, RetryPolicy,
+    };
+    use deadpool_postgres::Runtime;
+    use futures::future::join_all;
+    use tokio_postgres::NoTls;
+
+    const RETENTION: Duration = Duration::from_secs(60);
+
+    async fn repository() -> TransactionRepository {
+        let mut cfg = deadpool_postgres::Config::new();
+        cfg.url = Some(std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is needed"));
+        let conn = cfg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+        run_migrations(&conn).await.unwrap();
+
+        TransactionRepository { conn }
+    }
+
+    async fn balance(repo: &TransactionRepository, user_id: i32) -> i64 {
+        repo.get_account(user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .balance
+            .cents()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn idempotent_replays_first_outcome() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let first = Transaction::generate(50, None);
+        let applied = repo
+            .save_idempotent(acc.id, "k", &first, None, RETENTION, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(applied, Idempotent::Applied(Ok((_, id))) if id == Some(first.id)));
+
+        // a resubmission is a new transaction with the same payload
+        let again = Transaction::generate(50, None);
+        let replayed = repo
+            .save_idempotent(acc.id, "k", &again, None, RETENTION, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(replayed, Idempotent::Replayed(Ok((acc, id)))
+            if id == Some(first.id) && acc.balance.cents() == 50));
+        assert_eq!(balance(&repo, acc.id).await, 50);
+
+        let rejected = Transaction::generate(-500, None);
+        let outcome = repo
+            .save_idempotent(acc.id, "r", &rejected, None, RETENTION, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            Idempotent::Applied(Err(AccountError::InsufficientCredit))
+        ));
+        let outcome = repo
+            .save_idempotent(acc.id, "r", &rejected, None, RETENTION, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            Idempotent::Replayed(Err(AccountError::InsufficientCredit))
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn idempotent_refuses_other_transaction() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let first = Transaction::generate(50, None);
+        repo.save_idempotent(acc.id, "k", &first, None, RETENTION, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        for other in [
+            Transaction::generate(51, None),
+            Transaction::generate(-50, None),
+            Transaction::generate(50, "other"),
+        ] {
+            let outcome = repo
+                .save_idempotent(acc.id, "k", &other, None, RETENTION, None)
+                .await
+                .unwrap();
+            assert!(matches!(
+                outcome,
+                Err(TransactionError::IdempotencyKeyReused)
+            ));
+        }
+        assert_eq!(balance(&repo, acc.id).await, 50);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn idempotent_concurrent_first_submissions() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let submissions = (0..8).map(|_| Transaction::generate(-30, None));
+        let outcomes = join_all(submissions.map(|transaction| {
+            let repo = &repo;
+            async move {
+                repo.save_idempotent(acc.id, "k", &transaction, None, RETENTION, None)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        }))
+        .await;
+
+        let applied = outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, Idempotent::Applied(_)))
+            .count();
+        assert_eq!(applied, 1);
+
+        let ids = outcomes
+            .iter()
+            .map(|outcome| outcome.outcome().unwrap().1)
+            .collect::<Vec<_>>();
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        assert_eq!(balance(&repo, acc.id).await, -30);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn credit_limit_changes() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+        repo.save_and_get_balance(acc.id, &Transaction::generate(-80, None), None, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let acc = repo
+            .set_credit_limit(acc.id, Money::from_cents(80))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(acc.credit_limit.cents(), 80);
+        assert_eq!(acc.balance.cents(), -80);
+
+        let below = repo
+            .set_credit_limit(acc.id, Money::from_cents(79))
+            .await
+            .unwrap();
+        assert!(matches!(below, Err(AccountError::LimitBelowBalance)));
+
+        let missing = repo
+            .set_credit_limit(i32::MAX, Money::from_cents(0))
+            .await
+            .unwrap();
+        assert!(matches!(missing, Err(AccountError::NotFound)));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn closed_account_keeps_history_and_refuses_writes() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+        let other = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let credit = Transaction::generate(50, None);
+        repo.save_and_get_balance(acc.id, &credit, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let outcome = repo.close_account(acc.id).await.unwrap();
+        assert!(matches!(outcome, Err(AccountError::NonZeroBalance)));
+
+        let (mut debit, mut credit) = Transaction::transfer(
+            Money::from_cents(50),
+            TransactionDescription::with_rules("out", &DescriptionRules::default()).unwrap(),
+        );
+        repo.save_transfer(
+            acc.id,
+            other.id,
+            &mut debit,
+            &mut credit,
+            (None, None),
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        repo.close_account(acc.id).await.unwrap().unwrap();
+        assert!(repo.get_account(acc.id).await.unwrap().is_none());
+        assert!(repo
+            .get_accounts()
+            .await
+            .into_iter()
+            .all(|a| a.id != acc.id));
+
+        let history = repo
+            .get_transactions(acc.id, &StatementPage::default())
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(repo
+            .get_transaction(other.id, credit.id)
+            .await
+            .unwrap()
+            .is_some());
+
+        fn closed<T>(outcome: Result<T, AccountError>) -> bool {
+            matches!(outcome, Err(AccountError::Closed))
+        }
+        let deposit = Transaction::generate(10, None);
+        assert!(closed(
+            repo.save_and_get_balance(acc.id, &deposit, None, None)
+                .await
+                .unwrap()
+        ));
+        assert!(closed(
+            repo.set_credit_limit(acc.id, Money::from_cents(0))
+                .await
+                .unwrap()
+        ));
+        assert!(closed(repo.close_account(acc.id).await.unwrap()));
+
+        let (mut debit, mut credit) = Transaction::transfer(
+            Money::from_cents(10),
+            TransactionDescription::with_rules("in", &DescriptionRules::default()).unwrap(),
+        );
+        assert!(closed(
+            repo.save_transfer(
+                other.id,
+                acc.id,
+                &mut debit,
+                &mut credit,
+                (None, None),
+                None
+            )
+            .await
+            .unwrap()
+        ));
+        assert_eq!(balance(&repo, other.id).await, 50);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn only_forced_reversals_go_past_the_limit() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let credit = Transaction::generate(50, None);
+        repo.save_and_get_balance(acc.id, &credit, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        repo.save_and_get_balance(acc.id, &Transaction::generate(-150, None), None, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let refused = repo
+            .save_reversal(acc.id, credit.id, false, None, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            refused,
+            Err(ReversalError::Account(AccountError::InsufficientCredit))
+        ));
+        let (acc, _) = repo
+            .save_reversal(acc.id, credit.id, true, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(acc.balance.cents(), -150);
+
+        // whichever statement tries, the database won't go further
+        let conn = repo.conn.get().await.unwrap();
+        let overdraw = "UPDATE account SET balance = balance - 1 WHERE id = $1;";
+        assert!(conn.execute(overdraw, &[&acc.id]).await.is_err());
+        let repay = "UPDATE account SET balance = balance + 1 WHERE id = $1;";
+        conn.execute(repay, &[&acc.id]).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn idempotent_replays_keys_older_than_transaction_ids() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        // as stored before migration 0008
+        let conn = repo.conn.get().await.unwrap();
+        let query = r#"
+INSERT INTO idempotency_key (account_id, key, created_on, balance, credit_limit)
+VALUES ($1, 'old', $2, 50, 100);"#;
+        let created_on = to_timestamp(OffsetDateTime::now_utc());
+        conn.execute(query, &[&acc.id, &created_on]).await.unwrap();
+
+        let transaction = Transaction::generate(50, None);
+        let replayed = repo
+            .save_idempotent(acc.id, "old", &transaction, None, RETENTION, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(replayed, Idempotent::Replayed(Ok((acc, None)))
+            if acc.balance.cents() == 50));
+    }
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn stale_fencing_tokens_are_refused() {
+        let repo = repository().await;
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+        let other = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let credit = Transaction::generate(50, None);
+        repo.save_and_get_balance(acc.id, &credit, Some(2), None)
+            .await
+            .unwrap()
+            .unwrap();
+        let stale = Transaction::generate(10, None);
+        assert!(repo
+            .save_and_get_balance(acc.id, &stale, Some(1), None)
+            .await
+            .is_err());
+
+        let transfer = || {
+            Transaction::transfer(
+                Money::from_cents(10),
+                TransactionDescription::with_rules("out", &DescriptionRules::default()).unwrap(),
+            )
+        };
+        let (mut debit, mut credit_leg) = transfer();
+        assert!(repo
+            .save_transfer(
+                acc.id,
+                other.id,
+                &mut debit,
+                &mut credit_leg,
+                (Some(1), None),
+                None,
+            )
+            .await
+            .is_err());
+        let (mut debit, mut credit_leg) = transfer();
+        repo.save_transfer(
+            acc.id,
+            other.id,
+            &mut debit,
+            &mut credit_leg,
+            (Some(3), Some(1)),
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert!(repo
+            .save_reversal(acc.id, credit.id, false, Some(2), None)
+            .await
+            .is_err());
+        repo.save_reversal(other.id, credit_leg.id, false, Some(1), None)
+            .await
+            .unwrap()
+            .unwrap_err();
+        repo.save_reversal(acc.id, credit.id, false, Some(3), None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(balance(&repo, acc.id).await, -10);
+        assert_eq!(balance(&repo, other.id).await, 10);
+    }
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn advisory_lock_spans_the_write() {
+        let repo = repository().await;
+        let locks = AdvisoryLocks::new(repo.conn.clone(), RetryPolicy::quick(u32::MAX, 50));
+        let acc = repo.create_account(Money::from_cents(100)).await.unwrap();
+
+        let guard = locks.acquire(acc.id).await.unwrap();
+        let credit = Transaction::generate(50, None);
+        repo.save_and_get_balance(acc.id, &credit, None, guard.client())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(balance(&repo, acc.id).await, 0);
+
+        // giving up hands the connection back to the pool
+        let size = repo.conn.status().size;
+        assert!(matches!(
+            locks.acquire(acc.id).await,
+            Err(LockError::Timeout)
+        ));
+        assert_eq!(repo.conn.status().size, size);
+
+        // committed, but still locked
+        guard.commit().await.unwrap();
+        assert_eq!(balance(&repo, acc.id).await, 50);
+        assert!(matches!(
+            locks.acquire(acc.id).await,
+            Err(LockError::Timeout)
+        ));
+
+        let debit = Transaction::generate(-20, None);
+        repo.save_and_get_balance(acc.id, &debit, None, guard.client())
+            .await
+            .unwrap()
+            .unwrap();
+        guard.release().await.unwrap();
+        assert_eq!(balance(&repo, acc.id).await, 30);
+        locks
+            .acquire(acc.id)
+            .await
+            .unwrap()
+            .release()
             .await
-            .map(|_| ())
-            .or_else(|_| bail!("problem querying postgres"))
+            .unwrap();
     }
 }
@@ -3,10 +3,14 @@ use listenfd::ListenFd;
 use redis::aio::ConnectionManager;
 use redis::Client;
 use rinha_de_backend::application::cache::AccountCache;
+use rinha_de_backend::application::migrations::run_migrations;
 use rinha_de_backend::application::repositories::TransactionRepository;
-use rinha_de_backend::application::ServerData;
-use rinha_de_backend::infrastructure::server_impl::server::{match_routes, parse_http};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rinha_de_backend::application::{ServerData, ServiceConfig, StatementPage};
+use rinha_de_backend::infrastructure::lock::{AccountLocks, DistributedLock, LockGuard};
+use rinha_de_backend::infrastructure::server_impl::connection::{
+    handle_connection, ConnectionConfig,
+};
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio_postgres::NoTls;
 
@@ -31,20 +35,52 @@ async fn setup_pgsql() -> Pool {
     cfg.create_pool(Some(Runtime::Tokio1), NoTls).unwrap()
 }
 
-async fn setup_redis(pool: &Pool) -> ConnectionManager {
-    let conn = ConnectionManager::new(Client::open("redis://localhost:6379").unwrap())
+async fn setup_redis() -> ConnectionManager {
+    ConnectionManager::new(Client::open("redis://localhost:6379").unwrap())
         .await
-        .unwrap();
-
-    let repo = TransactionRepository { conn: pool.clone() };
+        .unwrap()
+}
 
-    let cache = AccountCache {
-        re_conn: conn.clone(),
+/// Caches every open account, each one under its lock so writes other instances make meanwhile
+/// aren't overwritten.
+async fn seed_cache(repo: &TransactionRepository, cache: &AccountCache, locks: &AccountLocks) {
+    let page = StatementPage {
+        limit: AccountCache::CACHED_TRANSACTIONS,
+        ..Default::default()
     };
     for acc in repo.get_accounts().await {
-        cache.save_account(acc.id, &acc, None).await;
+        let guard = locks.acquire(acc.id).await.unwrap();
+
+        // read again, it may have changed before we got the lock
+        if let Some(acc) = repo.get_account(acc.id).await.unwrap() {
+            let transactions = repo.get_transactions(acc.id, &page).await.unwrap();
+            match guard.extend().await {
+                Ok(()) => cache.seed_account(acc.id, &acc, &transactions).await,
+                // someone else may be writing it already, leave it to Postgres
+                Err(_) => cache.remove_account(acc.id).await,
+            }
+            .unwrap();
+        }
+
+        if let Err(err) = guard.release().await {
+            eprintln!("failed to release a lock after seeding; err = {err:?}");
+        }
+    }
+}
+
+/// How often expired idempotency keys are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Purges expired idempotency keys right away and then every [PURGE_INTERVAL], for as long as
+/// the server runs.
+async fn purge_idempotency_keys(repo: TransactionRepository, retention: Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = repo.purge_idempotency_keys(retention).await {
+            eprintln!("failed to purge idempotency keys; err = {err:?}");
+        }
     }
-    conn
 }
 
 async fn run() {
@@ -60,44 +96,44 @@ async fn run() {
     };
 
     let pg_pool = setup_pgsql().await;
-    let re_conn = setup_redis(&pg_pool).await;
-
-    let data = ServerData { re_conn, pg_pool };
+    run_migrations(&pg_pool).await.unwrap();
+    let re_conn = setup_redis().await;
+
+    let service_config = ServiceConfig::from_env().unwrap();
+    let locks = AccountLocks::new(
+        service_config.lock_backend,
+        &re_conn,
+        &pg_pool,
+        service_config.lock_ttl,
+        service_config.lock_retry,
+    );
+
+    let repo = TransactionRepository {
+        conn: pg_pool.clone(),
+    };
+    let cache = AccountCache {
+        re_conn: re_conn.clone(),
+    };
+    seed_cache(&repo, &cache, &locks).await;
+    tokio::spawn(purge_idempotency_keys(
+        repo,
+        service_config.idempotency_retention,
+    ));
+
+    let data = ServerData {
+        re_conn,
+        pg_pool,
+        config: service_config,
+        locks,
+    };
+    let config = ConnectionConfig::from_env();
 
     println!("Server is running!");
 
     loop {
-        let (mut stream, _) = socket.accept().await.unwrap();
+        let (stream, _) = socket.accept().await.unwrap();
         stream.set_nodelay(true).unwrap();
 
-        let data = data.clone();
-        tokio::spawn(async move {
-            // messages are not that long anyway
-            let mut buf = [0; 1024];
-
-            loop {
-                let read_bytes = match stream.read(&mut buf).await {
-                    Ok(n) => n,
-                    Err(e) => {
-                        eprintln!("failed to read from socket; err = {:?}", e);
-                        return;
-                    }
-                };
-
-                if read_bytes == 0 {
-                    return;
-                }
-
-                let request = parse_http(&buf).unwrap();
-                let response = match_routes(&data, request).await;
-                let response = if response.is_left() {
-                    response.unwrap_left()
-                } else {
-                    response.unwrap_right()
-                };
-                stream.write_all(&response.into_http()).await.unwrap();
-                // stream.shutdown().await.unwrap();
-            }
-        });
+        tokio::spawn(handle_connection(stream, data.clone(), config));
     }
 }
@@ -1,45 +1,58 @@
 use crate::domain::errors::AccountError;
+use crate::domain::money::Money;
 use crate::domain::transaction::Transaction;
 use serde::{Deserialize, Serialize};
 
 /// Aggregate
 ///
 /// Don't store the balance on the aggregate you dummkopf!
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
 pub struct Account {
     pub id: i32,
-    pub balance: i32,
-    pub credit_limit: u32,
+    pub balance: Money,
+    /// How far below zero the balance may go, never negative.
+    pub credit_limit: Money,
 }
 
 impl Account {
-    pub fn new(id: i32, credit_limit: u32) -> Self {
+    pub fn new(id: i32, credit_limit: Money) -> Self {
         Self {
             id,
-            balance: 0,
+            balance: Money::ZERO,
             credit_limit,
         }
     }
 
     #[cfg(test)]
-    fn generate(balance: i32, limit: u32) -> Account {
+    fn generate(balance: i64, limit: i64) -> Account {
         Account {
             id: -1,
-            balance,
-            credit_limit: limit,
+            balance: Money::from_cents(balance),
+            credit_limit: Money::from_cents(limit),
         }
     }
 
     /// Returns [Account] with the new transaction.
-    pub fn add_transaction(mut self, transaction: &Transaction) -> Result<Self, AccountError> {
-        let transaction_amount = transaction.valor;
-        let new_balance = self.balance + i32::from(transaction_amount);
+    pub fn add_transaction(self, transaction: &Transaction) -> Result<Self, AccountError> {
+        let acc = self.force_transaction(transaction)?;
 
-        if new_balance.is_negative() && new_balance.abs() > self.credit_limit as i32 {
+        let floor = self
+            .credit_limit
+            .checked_neg()
+            .ok_or(AccountError::BalanceOverflow)?;
+        if acc.balance < floor {
             return Err(AccountError::InsufficientCredit);
         }
 
-        self.balance = new_balance;
+        Ok(acc)
+    }
+
+    /// Returns [Account] with the new transaction, even past the credit limit.
+    pub fn force_transaction(mut self, transaction: &Transaction) -> Result<Self, AccountError> {
+        self.balance = self
+            .balance
+            .checked_add(transaction.valor)
+            .ok_or(AccountError::BalanceOverflow)?;
         Ok(self)
     }
 }
@@ -47,21 +60,22 @@ impl Account {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn failure_more_funds() {
         let account = Account::generate(1_000, 10_000);
-        assert_eq!(account.balance, 1_000);
+        assert_eq!(account.balance.cents(), 1_000);
 
         let with_new_transaction = account
             .add_transaction(&Transaction::generate(-1000, None))
             .unwrap();
-        assert_eq!(with_new_transaction.balance, 0);
+        assert_eq!(with_new_transaction.balance, Money::ZERO);
 
         let with_new_transaction = with_new_transaction
             .add_transaction(&Transaction::generate(-10_000, None))
             .unwrap();
-        assert_eq!(with_new_transaction.balance, -10_000);
+        assert_eq!(with_new_transaction.balance.cents(), -10_000);
 
         let with_new_transaction =
             with_new_transaction.add_transaction(&Transaction::generate(-1, None));
@@ -70,4 +84,54 @@ mod tests {
             _ => unreachable!(),
         }
     }
+
+    #[test]
+    fn failure_overflow() {
+        let account = Account::generate(i64::MAX, 0);
+        assert!(matches!(
+            account.add_transaction(&Transaction::generate(1, None)),
+            Err(AccountError::BalanceOverflow)
+        ));
+        assert!(matches!(
+            account.force_transaction(&Transaction::generate(1, None)),
+            Err(AccountError::BalanceOverflow)
+        ));
+
+        let account = Account::generate(-i64::MAX, i64::MAX);
+        assert!(matches!(
+            account.add_transaction(&Transaction::generate(-1, None)),
+            Err(AccountError::InsufficientCredit)
+        ));
+        assert!(matches!(
+            account.force_transaction(&Transaction::generate(-1, None)),
+            Ok(acc) if acc.balance == Money::MIN
+        ));
+        assert!(matches!(
+            account.force_transaction(&Transaction::generate(-2, None)),
+            Err(AccountError::BalanceOverflow)
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn balance_never_passes_the_limit(
+            balance in -i64::MAX..=i64::MAX,
+            limit in 0..=i64::MAX,
+            amount in any::<i64>().prop_filter("non zero", |amount| *amount != 0),
+        ) {
+            prop_assume!(balance >= -limit);
+            let account = Account::generate(balance, limit);
+
+            let wide = i128::from(balance) + i128::from(amount);
+            match account.add_transaction(&Transaction::generate(amount, None)) {
+                Ok(acc) => {
+                    prop_assert_eq!(i128::from(acc.balance.cents()), wide);
+                    prop_assert!(wide >= -i128::from(limit));
+                }
+                Err(AccountError::InsufficientCredit) => prop_assert!(wide < -i128::from(limit)),
+                Err(AccountError::BalanceOverflow) => prop_assert!(i64::try_from(wide).is_err()),
+                Err(err) => prop_assert!(false, "unexpected {:?}", err),
+            }
+        }
+    }
 }
@@ -1,11 +1,49 @@
 //! Domain Errors
 
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Copy, Clone)]
 pub enum TransactionError {
     InvalidDescription,
+    InvalidKind,
+    InvalidAmount,
+    NotFound,
+    AlreadyReversed,
+    /// Reversals and transfer legs can't be reversed.
+    NotReversible,
+    /// The idempotency key was first sent with another transaction.
+    IdempotencyKeyReused,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum AccountError {
     InsufficientCredit,
+    NotFound,
+    /// The balance is already below the credit limit asked for.
+    LimitBelowBalance,
+    /// Only accounts with nothing in them can be closed.
+    NonZeroBalance,
+    /// The balance would leave the range [Money](crate::domain::money::Money) can hold.
+    BalanceOverflow,
+    /// Closed accounts keep their history but take no more writes.
+    Closed,
+}
+
+/// Why a reversal was refused, either side may object.
+#[derive(Debug, Copy, Clone)]
+pub enum ReversalError {
+    Account(AccountError),
+    Transaction(TransactionError),
+}
+
+impl From<AccountError> for ReversalError {
+    fn from(value: AccountError) -> Self {
+        ReversalError::Account(value)
+    }
+}
+
+impl From<TransactionError> for ReversalError {
+    fn from(value: TransactionError) -> Self {
+        ReversalError::Transaction(value)
+    }
 }
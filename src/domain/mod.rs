@@ -3,3 +3,5 @@ pub mod account;
 pub mod transaction;
 
 pub mod errors;
+
+pub mod money;
@@ -0,0 +1,102 @@
+//! Amounts of money.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// An amount in cents, stored as `bigint` in Postgres.
+///
+/// Arithmetic is checked, the caller decides which domain error an overflow is.
+#[derive(
+    Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Self = Self(0);
+    pub const MIN: Self = Self(i64::MIN);
+    pub const MAX: Self = Self(i64::MAX);
+
+    pub const fn from_cents(cents: i64) -> Self {
+        Self(cents)
+    }
+
+    pub const fn cents(self) -> i64 {
+        self.0
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+
+    pub fn checked_neg(self) -> Option<Self> {
+        self.0.checked_neg().map(Self)
+    }
+
+    pub fn checked_abs(self) -> Option<Self> {
+        self.0.checked_abs().map(Self)
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub const fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+}
+
+impl Display for Money {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(Money::MAX.checked_add(Money::from_cents(1)), None);
+        assert_eq!(Money::MIN.checked_sub(Money::from_cents(1)), None);
+        assert_eq!(Money::MIN.checked_neg(), None);
+        assert_eq!(Money::MIN.checked_abs(), None);
+        assert_eq!(Money::MAX.checked_neg(), Some(Money::from_cents(-i64::MAX)));
+        assert_eq!(
+            Money::MAX.checked_add(Money::MIN),
+            Some(Money::from_cents(-1))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn add_matches_wide_math(a: i64, b: i64) {
+            let wide = i64::try_from(i128::from(a) + i128::from(b)).ok();
+            let sum = Money::from_cents(a).checked_add(Money::from_cents(b));
+            prop_assert_eq!(sum.map(Money::cents), wide);
+        }
+
+        #[test]
+        fn sub_matches_wide_math(a: i64, b: i64) {
+            let wide = i64::try_from(i128::from(a) - i128::from(b)).ok();
+            let diff = Money::from_cents(a).checked_sub(Money::from_cents(b));
+            prop_assert_eq!(diff.map(Money::cents), wide);
+        }
+
+        #[test]
+        fn neg_round_trips(a in (i64::MIN + 1)..=i64::MAX) {
+            let money = Money::from_cents(a);
+            prop_assert_eq!(money.checked_neg().and_then(Money::checked_neg), Some(money));
+        }
+    }
+}
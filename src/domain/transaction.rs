@@ -1,16 +1,114 @@
-use crate::AnyResult;
+use crate::domain::errors::TransactionError;
+use crate::domain::money::Money;
 use compact_str::CompactString;
-use eyre::bail;
-use serde::{Deserialize, Serialize};
-use std::num::NonZeroI32;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Display, Formatter};
+use std::hash::Hasher;
+use std::str::FromStr;
 use time::OffsetDateTime;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
-    pub valor: NonZeroI32,
+    pub id: TransactionId,
+    /// Never zero, negative for debits.
+    pub valor: Money,
     pub tipo: TransactionKind,
     pub descricao: TransactionDescription,
     pub realizada_em: OffsetDateTime,
+    /// Transfer this transaction is a leg of, shared with the leg in the other account.
+    pub transferencia: Option<i64>,
+    /// Transaction this one reverses.
+    pub estorno_de: Option<TransactionId>,
+}
+
+/// Globally unique transaction id, laid out as a UUIDv7: milliseconds since the epoch come
+/// first, so ids sort by creation time, the rest is random.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TransactionId(u128);
+
+impl TransactionId {
+    const VERSION: u128 = 0x7 << 76;
+    const VARIANT: u128 = 0b10 << 62;
+
+    /// A new id for a transaction created now.
+    pub fn now() -> Self {
+        Self::at(OffsetDateTime::now_utc(), fastrand::u128(..))
+    }
+
+    fn at(time: OffsetDateTime, random: u128) -> Self {
+        let millis = (time.unix_timestamp_nanos() / 1_000_000) as u128 & ((1 << 48) - 1);
+        let random = random & !(0xf << 76) & !(0b11 << 62) & ((1 << 80) - 1);
+        Self(millis << 80 | Self::VERSION | Self::VARIANT | random)
+    }
+
+    pub const fn from_u128(id: u128) -> Self {
+        Self(id)
+    }
+
+    pub const fn as_u128(self) -> u128 {
+        self.0
+    }
+}
+
+/// Hyphenated lowercase hex, as UUIDs usually are.
+impl Display for TransactionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+/// Malformed ids are [TransactionError::NotFound], no transaction could have them.
+impl FromStr for TransactionId {
+    type Err = TransactionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hyphens_at = |i| matches!(i, 8 | 13 | 18 | 23);
+        let well_formed = s.len() == 36
+            && s.char_indices().all(|(i, c)| match c {
+                '-' => hyphens_at(i),
+                _ => !hyphens_at(i) && c.is_ascii_hexdigit(),
+            });
+        if !well_formed {
+            return Err(TransactionError::NotFound);
+        }
+
+        let hex = s.replace('-', "");
+        u128::from_str_radix(&hex, 16)
+            .map(Self)
+            .map_err(|_| TransactionError::NotFound)
+    }
+}
+
+/// A string in JSON, a plain number in the binary cache encoding.
+impl Serialize for TransactionId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_u128(self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let id = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+            id.parse()
+                .map_err(|_| serde::de::Error::custom("invalid transaction id"))
+        } else {
+            u128::deserialize(deserializer).map(Self)
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -19,22 +117,115 @@ pub enum TransactionKind {
     Debit,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct TransactionDescription(pub CompactString);
 
 impl TransactionDescription {
-    pub fn new(description: &str) -> AnyResult<Self> {
-        if description.len() > 10 && description.is_empty() {
-            bail!("Invalid length");
+    pub fn with_rules(
+        description: &str,
+        rules: &DescriptionRules,
+    ) -> Result<Self, TransactionError> {
+        // characters as in Unicode scalar values, not bytes
+        let len = description.chars().count();
+        if !(rules.min_len..=rules.max_len).contains(&len)
+            || description.chars().any(char::is_control)
+        {
+            return Err(TransactionError::InvalidDescription);
         }
 
         Ok(Self(description.into()))
     }
 }
 
+/// What makes a valid [TransactionDescription], besides having no control characters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DescriptionRules {
+    pub min_len: usize,
+    pub max_len: usize,
+}
+
+impl Default for DescriptionRules {
+    fn default() -> Self {
+        Self {
+            min_len: 1,
+            max_len: 10,
+        }
+    }
+}
+
+impl DescriptionRules {
+    /// `None` for rules no description could satisfy.
+    pub fn new(min_len: usize, max_len: usize) -> Option<Self> {
+        (min_len > 0 && min_len <= max_len).then_some(Self { min_len, max_len })
+    }
+}
+
 impl Transaction {
+    /// Debit and credit legs of a transfer of `amount`, linked once the transfer is saved.
+    ///
+    /// `amount` is positive, it's validated with the rest of the transfer payload.
+    pub fn transfer(amount: Money, description: TransactionDescription) -> (Self, Self) {
+        let credit = Self {
+            id: TransactionId::now(),
+            valor: amount,
+            tipo: TransactionKind::Credit,
+            descricao: description,
+            realizada_em: OffsetDateTime::now_utc(),
+            transferencia: None,
+            estorno_de: None,
+        };
+        let debit = Self {
+            id: TransactionId::now(),
+            // positive amounts always have a negative counterpart
+            valor: amount.checked_neg().expect("No reason to fail."),
+            tipo: TransactionKind::Debit,
+            ..credit.clone()
+        };
+
+        (debit, credit)
+    }
+
+    /// Compensating transaction undoing this one.
+    ///
+    /// Neither reversals nor transfer legs can be reversed, as reversing one leg alone would
+    /// leave the transfer half done.
+    pub fn reversal(&self) -> Result<Self, TransactionError> {
+        if self.estorno_de.is_some() || self.transferencia.is_some() {
+            return Err(TransactionError::NotReversible);
+        }
+
+        Ok(Self {
+            id: TransactionId::now(),
+            valor: self
+                .valor
+                .checked_neg()
+                .ok_or(TransactionError::InvalidAmount)?,
+            tipo: match self.tipo {
+                TransactionKind::Credit => TransactionKind::Debit,
+                TransactionKind::Debit => TransactionKind::Credit,
+            },
+            descricao: self.descricao.clone(),
+            realizada_em: OffsetDateTime::now_utc(),
+            transferencia: None,
+            estorno_de: Some(self.id),
+        })
+    }
+
+    /// FNV-1a of what the client asked for: amount, kind and description. Stable across builds,
+    /// so it can be stored and compared with a later submission.
+    pub fn fingerprint(&self) -> i64 {
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(&self.valor.cents().to_le_bytes());
+        hasher.write_u8(match self.tipo {
+            TransactionKind::Credit => b'c',
+            TransactionKind::Debit => b'd',
+        });
+        hasher.write(self.descricao.0.as_bytes());
+        hasher.finish() as i64
+    }
+
     #[cfg(test)]
-    pub fn generate<T>(amount: i32, description: T) -> Self
+    pub fn generate<T>(amount: i64, description: T) -> Self
     where
         T: Into<Option<&'static str>>,
     {
@@ -44,12 +235,167 @@ impl Transaction {
             TransactionKind::Credit
         };
 
-        let description = TransactionDescription::new(description.into().unwrap_or("xxx"));
+        let description = TransactionDescription::with_rules(
+            description.into().unwrap_or("xxx"),
+            &DescriptionRules::default(),
+        );
         Self {
-            valor: NonZeroI32::new(amount).expect("Safe to unwrap"),
+            id: TransactionId::now(),
+            valor: Money::from_cents(amount),
             tipo: kind,
             descricao: description.unwrap(),
             realizada_em: OffsetDateTime::now_utc(),
+            transferencia: None,
+            estorno_de: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_valid(description: &str) -> bool {
+        TransactionDescription::with_rules(description, &DescriptionRules::default()).is_ok()
+    }
+
+    #[test]
+    fn unsatisfiable_description_rules() {
+        assert_eq!(DescriptionRules::new(0, 10), None);
+        assert_eq!(DescriptionRules::new(11, 10), None);
+        assert!(DescriptionRules::new(10, 10).is_some());
+        assert_eq!(
+            DescriptionRules::new(1, 10),
+            Some(DescriptionRules::default())
+        );
+    }
+
+    #[test]
+    fn description_length() {
+        assert!(!is_valid(""));
+        assert!(is_valid("a"));
+        assert!(is_valid("0123456789"));
+        assert!(!is_valid("0123456789a"));
+        assert!(!is_valid(&"x".repeat(1024 * 1024)));
+    }
+
+    #[test]
+    fn description_counts_characters() {
+        // 10 characters, 20 bytes
+        assert!(is_valid("pãopãopãoç"));
+        assert!(is_valid("🦀🦀🦀🦀🦀🦀🦀🦀🦀🦀"));
+        assert!(!is_valid("🦀🦀🦀🦀🦀🦀🦀🦀🦀🦀🦀"));
+    }
+
+    #[test]
+    fn description_without_control_characters() {
+        assert!(!is_valid("a\nb"));
+        assert!(!is_valid("\t"));
+        assert!(!is_valid("nul\0"));
+        assert!(!is_valid("del\u{7f}"));
+        assert!(is_valid("two words"));
+    }
+
+    #[test]
+    fn description_custom_rules() {
+        let rules = DescriptionRules {
+            min_len: 3,
+            max_len: 20,
+        };
+        assert!(TransactionDescription::with_rules("ab", &rules).is_err());
+        assert!(TransactionDescription::with_rules("abc", &rules).is_ok());
+        assert!(TransactionDescription::with_rules("a longer description", &rules).is_ok());
+        assert!(TransactionDescription::with_rules("a longer description!", &rules).is_err());
+    }
+
+    #[test]
+    fn reversal_compensates() {
+        let transaction = Transaction::generate(-100, "pix");
+        let reversal = transaction.reversal().unwrap();
+        assert_eq!(reversal.valor.cents(), 100);
+        assert!(matches!(reversal.tipo, TransactionKind::Credit));
+        assert_eq!(reversal.descricao, transaction.descricao);
+        assert_eq!(reversal.estorno_de, Some(transaction.id));
+        assert_ne!(reversal.id, transaction.id);
+
+        assert!(matches!(
+            reversal.reversal(),
+            Err(TransactionError::NotReversible)
+        ));
+    }
+
+    #[test]
+    fn transfer_legs_are_not_reversible() {
+        let amount = Money::from_cents(50);
+        let description =
+            TransactionDescription::with_rules("rent", &DescriptionRules::default()).unwrap();
+        let (mut debit, credit) = Transaction::transfer(amount, description);
+        assert_eq!(debit.valor.cents(), -50);
+        assert_eq!(credit.valor.cents(), 50);
+        assert_ne!(debit.id, credit.id);
+
+        debit.transferencia = Some(1);
+        assert!(matches!(
+            debit.reversal(),
+            Err(TransactionError::NotReversible)
+        ));
+    }
+
+    #[test]
+    fn transaction_id_layout() {
+        let time = OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_123_000_000).unwrap();
+        let id = TransactionId::at(time, u128::MAX);
+        assert_eq!(id.to_string(), "018bcfe5-687b-7fff-bfff-ffffffffffff");
+
+        let id = TransactionId::at(time, 0);
+        assert_eq!(id.to_string(), "018bcfe5-687b-7000-8000-000000000000");
+    }
+
+    #[test]
+    fn transaction_id_sorts_by_time() {
+        let earlier = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let later = earlier + time::Duration::milliseconds(1);
+        assert!(TransactionId::at(earlier, u128::MAX) < TransactionId::at(later, 0));
+        assert!(TransactionId::now() > TransactionId::at(later, u128::MAX));
+    }
+
+    #[test]
+    fn transaction_id_round_trip() {
+        let id = TransactionId::now();
+        assert_eq!(id.to_string().parse::<TransactionId>().unwrap(), id);
+        assert_eq!(
+            "018BCFE5-687B-7000-8000-000000000000"
+                .parse::<TransactionId>()
+                .unwrap()
+                .to_string(),
+            "018bcfe5-687b-7000-8000-000000000000"
+        );
+
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{id}\""));
+        assert_eq!(serde_json::from_str::<TransactionId>(&json).unwrap(), id);
+        let bytes = bitcode::serialize(&id).unwrap();
+        assert_eq!(bitcode::deserialize::<TransactionId>(&bytes).unwrap(), id);
+
+        for invalid in [
+            "",
+            "7",
+            "018bcfe5687b70008000000000000000",
+            "018bcfe5-687b-7000-8000-00000000000g",
+            "+18bcfe5-687b-7000-8000-000000000000",
+        ] {
+            assert!(invalid.parse::<TransactionId>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn fingerprint_covers_the_payload() {
+        let transaction = Transaction::generate(-10, "abc");
+        let fingerprint = transaction.fingerprint();
+
+        assert_eq!(Transaction::generate(-10, "abc").fingerprint(), fingerprint);
+        assert_ne!(Transaction::generate(10, "abc").fingerprint(), fingerprint);
+        assert_ne!(Transaction::generate(-11, "abc").fingerprint(), fingerprint);
+        assert_ne!(Transaction::generate(-10, "abd").fingerprint(), fingerprint);
+    }
+}
@@ -0,0 +1,562 @@
+//! Account locks serializing writes to an account, across instances or within one.
+//!
+//! [DistributedLock] is implemented by [RedisLocks], by [AdvisoryLocks] on Postgres and by
+//! [InProcessLocks] for a single instance, [AccountLocks] picks one at startup.
+
+use crate::infrastructure::redis_lock::{RedisLockGuard, RedisLocks};
+use deadpool_postgres::{Object, Pool, PoolError};
+use fnv::FnvHashMap;
+use redis::aio::ConnectionManager;
+use redis::RedisError;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use strum::EnumString;
+use tokio::sync::OwnedMutexGuard;
+use tokio::time::Instant;
+
+/// Locks accounts, one holder at a time.
+pub trait DistributedLock: Send + Sync {
+    type Guard: LockGuard;
+
+    /// Takes the lock on `account`, waiting for its holder if there's one.
+    fn acquire(&self, account: i32) -> impl Future<Output = Result<Self::Guard, LockError>> + Send;
+}
+
+/// Held lock. Dropping it without [LockGuard::release] frees it too, as soon as the backend
+/// allows.
+pub trait LockGuard: Send {
+    /// Token writes made under the lock should carry, `None` for locks that can't expire while
+    /// held.
+    fn fencing_token(&self) -> Option<FencingToken>;
+
+    /// Keeps the lock for longer, [LockError::Lost] if it already expired. Locks that can't
+    /// expire while held have nothing to do.
+    fn extend(&self) -> impl Future<Output = Result<(), LockError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Connection holding the lock, for locks living in Postgres. Writes made under the lock go
+    /// through its open transaction, and commit on [LockGuard::commit].
+    fn client(&self) -> Option<&Object> {
+        None
+    }
+
+    /// Commits the writes made through [LockGuard::client] so far, keeping the lock. Writes made
+    /// under other locks commit as they're made, there's nothing to do.
+    fn commit(&self) -> impl Future<Output = Result<(), LockError>> + Send {
+        async { Ok(()) }
+    }
+
+    fn release(self) -> impl Future<Output = Result<(), LockError>> + Send;
+}
+
+/// Number handed out with each acquisition, greater than every one handed out before for the
+/// same account.
+///
+/// Writes carry it so the database can refuse the ones from a holder whose lock expired while it
+/// was paused, after someone else took the lock.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FencingToken(i64);
+
+impl FencingToken {
+    pub fn new(token: i64) -> Self {
+        Self(token)
+    }
+
+    pub fn get(self) -> i64 {
+        self.0
+    }
+}
+
+/// How [DistributedLock::acquire] waits for a lock someone else holds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries before giving up, the first one included.
+    pub max_attempts: u32,
+    /// Wait after the first failed try, doubled after each one.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Time from the first try after which acquiring gives up, whatever the attempts left.
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 20,
+            base_delay: Duration::from_millis(2),
+            max_delay: Duration::from_millis(50),
+            deadline: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after the failed try number `attempt`, counting from 0. Half of it is random, so
+    /// contenders that failed together don't retry together.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(31))
+            .min(self.max_delay);
+        let half = delay / 2;
+        let jitter = fastrand::u64(..=half.as_micros() as u64);
+        half + Duration::from_micros(jitter)
+    }
+
+    /// Calls `try_lock` until it returns the lock, `None` meaning someone else holds it.
+    pub async fn retry<T, F>(&self, mut try_lock: impl FnMut() -> F) -> Result<T, LockError>
+    where
+        F: Future<Output = Result<Option<T>, LockError>>,
+    {
+        let deadline = Instant::now() + self.deadline;
+
+        for attempt in 0..self.max_attempts {
+            if attempt > 0 {
+                let wake = Instant::now() + self.delay(attempt - 1);
+                if wake > deadline {
+                    return Err(LockError::Timeout);
+                }
+                tokio::time::sleep_until(wake).await;
+            }
+
+            let taken = tokio::time::timeout_at(deadline, try_lock())
+                .await
+                .map_err(|_| LockError::Timeout)??;
+            if let Some(taken) = taken {
+                return Ok(taken);
+            }
+        }
+
+        Err(LockError::Contended)
+    }
+}
+
+#[cfg(test)]
+impl RetryPolicy {
+    /// Retries every millisecond or two, for tests.
+    pub(crate) fn quick(max_attempts: u32, deadline_ms: u64) -> Self {
+        Self {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            deadline: Duration::from_millis(deadline_ms),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Someone else held the lock through every attempt.
+    Contended,
+    /// The deadline passed before the lock could be taken.
+    Timeout,
+    /// The lock expired, someone else may hold it now.
+    Lost,
+    Redis(RedisError),
+    Postgres(PoolError),
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Contended => f.write_str("lock is held by someone else"),
+            LockError::Timeout => f.write_str("timed out waiting for the lock"),
+            LockError::Lost => f.write_str("lock expired before being released"),
+            LockError::Redis(err) => write!(f, "lock unavailable: {err}"),
+            LockError::Postgres(err) => write!(f, "lock unavailable: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::Redis(err) => Some(err),
+            LockError::Postgres(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RedisError> for LockError {
+    fn from(value: RedisError) -> Self {
+        LockError::Redis(value)
+    }
+}
+
+impl From<PoolError> for LockError {
+    fn from(value: PoolError) -> Self {
+        LockError::Postgres(value)
+    }
+}
+
+impl From<tokio_postgres::Error> for LockError {
+    fn from(value: tokio_postgres::Error) -> Self {
+        LockError::Postgres(value.into())
+    }
+}
+
+/// Where account locks live, set with `LOCK_BACKEND`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum LockBackend {
+    #[default]
+    Redis,
+    Postgres,
+    /// Only for a single instance, other instances won't see the locks.
+    InProcess,
+}
+
+/// The [DistributedLock] picked at startup.
+#[derive(Debug, Clone)]
+pub enum AccountLocks {
+    Redis(RedisLocks),
+    Postgres(AdvisoryLocks),
+    InProcess(InProcessLocks),
+}
+
+impl AccountLocks {
+    /// Locks of `backend`. `ttl_max` only bounds Redis locks, the others are held until released.
+    pub fn new(
+        backend: LockBackend,
+        re_conn: &ConnectionManager,
+        pg_pool: &Pool,
+        ttl_max: Duration,
+        retry: RetryPolicy,
+    ) -> Self {
+        match backend {
+            LockBackend::Redis => {
+                AccountLocks::Redis(RedisLocks::new(re_conn.clone(), ttl_max, retry))
+            }
+            LockBackend::Postgres => {
+                AccountLocks::Postgres(AdvisoryLocks::new(pg_pool.clone(), retry))
+            }
+            LockBackend::InProcess => AccountLocks::InProcess(InProcessLocks::new(retry)),
+        }
+    }
+}
+
+impl DistributedLock for AccountLocks {
+    type Guard = AccountLockGuard;
+
+    async fn acquire(&self, account: i32) -> Result<AccountLockGuard, LockError> {
+        match self {
+            AccountLocks::Redis(locks) => locks.acquire(account).await.map(AccountLockGuard::Redis),
+            AccountLocks::Postgres(locks) => {
+                locks.acquire(account).await.map(AccountLockGuard::Postgres)
+            }
+            AccountLocks::InProcess(locks) => locks
+                .acquire(account)
+                .await
+                .map(AccountLockGuard::InProcess),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AccountLockGuard {
+    Redis(RedisLockGuard),
+    Postgres(AdvisoryLockGuard),
+    InProcess(InProcessLockGuard),
+}
+
+impl LockGuard for AccountLockGuard {
+    fn fencing_token(&self) -> Option<FencingToken> {
+        match self {
+            AccountLockGuard::Redis(guard) => guard.fencing_token(),
+            AccountLockGuard::Postgres(guard) => guard.fencing_token(),
+            AccountLockGuard::InProcess(guard) => guard.fencing_token(),
+        }
+    }
+
+    async fn extend(&self) -> Result<(), LockError> {
+        match self {
+            AccountLockGuard::Redis(guard) => guard.extend().await,
+            AccountLockGuard::Postgres(guard) => guard.extend().await,
+            AccountLockGuard::InProcess(guard) => guard.extend().await,
+        }
+    }
+
+    fn client(&self) -> Option<&Object> {
+        match self {
+            AccountLockGuard::Redis(guard) => guard.client(),
+            AccountLockGuard::Postgres(guard) => guard.client(),
+            AccountLockGuard::InProcess(guard) => guard.client(),
+        }
+    }
+
+    async fn commit(&self) -> Result<(), LockError> {
+        match self {
+            AccountLockGuard::Redis(guard) => guard.commit().await,
+            AccountLockGuard::Postgres(guard) => guard.commit().await,
+            AccountLockGuard::InProcess(guard) => guard.commit().await,
+        }
+    }
+
+    async fn release(self) -> Result<(), LockError> {
+        match self {
+            AccountLockGuard::Redis(guard) => guard.release().await,
+            AccountLockGuard::Postgres(guard) => guard.release().await,
+            AccountLockGuard::InProcess(guard) => guard.release().await,
+        }
+    }
+}
+
+/// Session-level advisory locks, each held by a pooled connection with an open transaction.
+#[derive(Debug, Clone)]
+pub struct AdvisoryLocks {
+    pool: Pool,
+    retry: RetryPolicy,
+}
+
+impl AdvisoryLocks {
+    pub fn new(pool: Pool, retry: RetryPolicy) -> Self {
+        Self { pool, retry }
+    }
+
+    /// First key of the two-key advisory locks, so account ids don't collide with other locks.
+    const NAMESPACE: i32 = 0x6163_6374;
+}
+
+impl DistributedLock for AdvisoryLocks {
+    type Guard = AdvisoryLockGuard;
+
+    async fn acquire(&self, account: i32) -> Result<AdvisoryLockGuard, LockError> {
+        // writes wait for a connection too, a drained pool mustn't keep them waiting for good
+        let conn = tokio::time::timeout(self.retry.deadline, self.pool.get())
+            .await
+            .map_err(|_| LockError::Timeout)??;
+        let stmt = conn
+            .prepare_cached("SELECT pg_try_advisory_lock($1, $2);")
+            .await?;
+        // giving up hands the connection back to the pool, it holds nothing
+        self.retry
+            .retry(|| async {
+                let row = conn.query_one(&stmt, &[&Self::NAMESPACE, &account]).await?;
+                Ok(row.get::<usize, bool>(0).then_some(()))
+            })
+            .await?;
+
+        let guard = AdvisoryLockGuard { conn: Some(conn) };
+        guard
+            .client()
+            .expect("No reason to fail.")
+            .batch_execute("BEGIN;")
+            .await?;
+        Ok(guard)
+    }
+}
+
+/// Connection holding an advisory lock until it's unlocked or closed. Writes made under the lock
+/// join the transaction open on it, see [LockGuard::client].
+pub struct AdvisoryLockGuard {
+    conn: Option<Object>,
+}
+
+impl Debug for AdvisoryLockGuard {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdvisoryLockGuard").finish_non_exhaustive()
+    }
+}
+
+impl LockGuard for AdvisoryLockGuard {
+    /// The lock lasts as long as the connection, it can't expire.
+    fn fencing_token(&self) -> Option<FencingToken> {
+        None
+    }
+
+    fn client(&self) -> Option<&Object> {
+        self.conn.as_ref()
+    }
+
+    /// Starts a new transaction for the writes to follow.
+    async fn commit(&self) -> Result<(), LockError> {
+        let conn = self.conn.as_ref().expect("No reason to fail.");
+        conn.batch_execute("COMMIT; BEGIN;").await?;
+        Ok(())
+    }
+
+    /// Commits the writes made since the last [LockGuard::commit], and frees the lock.
+    async fn release(self) -> Result<(), LockError> {
+        self.end("COMMIT; SELECT pg_advisory_unlock_all();").await
+    }
+}
+
+impl AdvisoryLockGuard {
+    /// Ends the transaction and frees the lock with `statement`, the connection goes back to the
+    /// pool.
+    async fn end(mut self, statement: &str) -> Result<(), LockError> {
+        let conn = self.conn.take().expect("No reason to fail.");
+        if let Err(err) = conn.batch_execute(statement).await {
+            // the transaction or the lock may still be held
+            drop(Object::take(conn));
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AdvisoryLockGuard {
+    fn drop(&mut self) {
+        // only when a panic unwinds past the guard, or its transaction couldn't start: the lock
+        // is still held, the connection can't go back to the pool. Closing it rolls the writes
+        // back and frees the lock
+        if let Some(conn) = self.conn.take() {
+            drop(Object::take(conn));
+        }
+    }
+}
+
+type AccountMutexes = Arc<Mutex<FnvHashMap<i32, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Locks in this process' memory, for single instance deployments and tests.
+#[derive(Debug, Clone)]
+pub struct InProcessLocks {
+    /// One mutex per account locked or waited for
+    locks: AccountMutexes,
+    retry: RetryPolicy,
+}
+
+impl InProcessLocks {
+    pub fn new(retry: RetryPolicy) -> Self {
+        Self {
+            locks: Arc::default(),
+            retry,
+        }
+    }
+}
+
+impl DistributedLock for InProcessLocks {
+    type Guard = InProcessLockGuard;
+
+    /// Waits in line for the holder, only [RetryPolicy::deadline] applies.
+    async fn acquire(&self, account: i32) -> Result<InProcessLockGuard, LockError> {
+        let mutex = {
+            let mut locks = self.locks.lock().unwrap();
+            locks.entry(account).or_default().clone()
+        };
+        let mut guard = InProcessLockGuard {
+            guard: None,
+            locks: self.locks.clone(),
+            account,
+        };
+
+        // giving up drops the guard, which forgets the mutex if nobody else waits for it
+        let taken = tokio::time::timeout(self.retry.deadline, mutex.lock_owned())
+            .await
+            .map_err(|_| LockError::Timeout)?;
+        guard.guard = Some(taken);
+        Ok(guard)
+    }
+}
+
+#[derive(Debug)]
+pub struct InProcessLockGuard {
+    guard: Option<OwnedMutexGuard<()>>,
+    locks: AccountMutexes,
+    account: i32,
+}
+
+impl LockGuard for InProcessLockGuard {
+    /// Nothing outlives the process, the lock can't expire.
+    fn fencing_token(&self) -> Option<FencingToken> {
+        None
+    }
+
+    async fn release(self) -> Result<(), LockError> {
+        drop(self);
+        Ok(())
+    }
+}
+
+impl Drop for InProcessLockGuard {
+    /// Frees the lock, and forgets the account's mutex unless someone else is waiting for it.
+    fn drop(&mut self) {
+        drop(self.guard.take());
+
+        let mut locks = self.locks.lock().unwrap();
+        if locks
+            .get(&self.account)
+            .is_some_and(|mutex| Arc::strong_count(mutex) == 1)
+        {
+            locks.remove(&self.account);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_names() {
+        assert_eq!("redis".parse(), Ok(LockBackend::Redis));
+        assert_eq!("postgres".parse(), Ok(LockBackend::Postgres));
+        assert_eq!("in_process".parse(), Ok(LockBackend::InProcess));
+        assert!("memcached".parse::<LockBackend>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_process_locks_per_account() {
+        let locks = InProcessLocks::new(RetryPolicy::quick(3, 1000));
+
+        let guard = locks.acquire(1).await.unwrap();
+        assert_eq!(guard.fencing_token(), None);
+        assert!(matches!(locks.acquire(1).await, Err(LockError::Timeout)));
+        let other = locks.acquire(2).await.unwrap();
+
+        guard.release().await.unwrap();
+        let guard = locks.acquire(1).await.unwrap();
+        drop(guard);
+        drop(other);
+        locks.acquire(1).await.unwrap().release().await.unwrap();
+        assert!(locks.locks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_process_locks_wait_for_the_holder() {
+        let locks = InProcessLocks::new(RetryPolicy::quick(u32::MAX, 20));
+        let guard = locks.acquire(1).await.unwrap();
+        assert!(matches!(locks.acquire(1).await, Err(LockError::Timeout)));
+        drop(guard);
+
+        let locks = InProcessLocks::new(RetryPolicy::quick(u32::MAX, 1000));
+        let guard = locks.acquire(1).await.unwrap();
+        let contender = locks.clone();
+        let waiting = tokio::spawn(async move { contender.acquire(1).await.is_ok() });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(guard);
+        assert!(waiting.await.unwrap());
+        assert!(locks.locks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        let retry = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(4),
+            max_delay: Duration::from_millis(40),
+            deadline: Duration::from_secs(1),
+        };
+
+        for (attempt, full) in [
+            (0, 4),
+            (1, 8),
+            (2, 16),
+            (3, 32),
+            (4, 40),
+            (30, 40),
+            (99, 40),
+        ] {
+            let full = Duration::from_millis(full);
+            for _ in 0..100 {
+                let delay = retry.delay(attempt);
+                assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+            }
+        }
+    }
+}
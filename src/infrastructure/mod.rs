@@ -1,3 +1,5 @@
 pub mod server_impl;
 
-pub mod redis_lock;
\ No newline at end of file
+pub mod lock;
+
+pub mod redis_lock;
@@ -1,106 +1,486 @@
+use crate::infrastructure::lock::{
+    DistributedLock, FencingToken, LockError, LockGuard, RetryPolicy,
+};
 use compact_str::CompactString;
-use drop_bomb::DropBomb;
-use redis::aio::{ConnectionLike, ConnectionManager};
-use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions, Value};
-use std::iter::repeat_with;
+use redis::aio::ConnectionManager;
+use redis::RedisError;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::time::Duration;
 
+/// Lock on one account, shared by every instance through Redis.
 #[derive(Clone)]
-pub struct RedisLock {
-    rconn: ConnectionManager,
-    /// A random alphanumeric string
+pub struct RedisLock<S = ConnectionManager> {
+    store: S,
+    /// Key held while the account is locked
     resource: CompactString,
-    /// Value the lock is tied to
-    val: i32,
-    ttl_max: usize,
+    /// Counter the fencing tokens come from, never expires
+    fencing: CompactString,
+    ttl_max: Duration,
+    retry: RetryPolicy,
+}
+
+impl<S> Debug for RedisLock<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisLock")
+            .field("resource", &self.resource)
+            .field("ttl_max", &self.ttl_max)
+            .field("retry", &self.retry)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where locks live, Redis outside of tests.
+pub trait LockStore: Clone + Send + Sync + 'static {
+    /// Takes `resource` for `ttl` and returns a token from the `fencing` counter, `None` when
+    /// someone else holds it.
+    fn try_lock(
+        &self,
+        resource: &str,
+        fencing: &str,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<Option<FencingToken>, RedisError>> + Send;
+
+    /// Frees `resource` if `token` still holds it, returns whether it did.
+    fn unlock(
+        &self,
+        resource: &str,
+        token: FencingToken,
+    ) -> impl Future<Output = Result<bool, RedisError>> + Send;
+
+    /// Makes `resource` expire `ttl` from now if `token` still holds it, returns whether it did.
+    fn extend(
+        &self,
+        resource: &str,
+        token: FencingToken,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<bool, RedisError>> + Send;
 }
 
-pub struct RedisLockGuard<'a> {
-    lock: &'a RedisLock,
-    bomb: DropBomb,
+impl LockStore for ConnectionManager {
+    async fn try_lock(
+        &self,
+        resource: &str,
+        fencing: &str,
+        ttl: Duration,
+    ) -> Result<Option<FencingToken>, RedisError> {
+        let token = redis::Script::new(LOCK_SCRIPT)
+            .key(resource)
+            .key(fencing)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async::<_, i64>(&mut self.clone())
+            .await?;
+
+        Ok((token > 0).then(|| FencingToken::new(token)))
+    }
+
+    async fn unlock(&self, resource: &str, token: FencingToken) -> Result<bool, RedisError> {
+        let res = redis::Script::new(DROP_SCRIPT)
+            .key(resource)
+            .arg(token.get())
+            .invoke_async::<_, i32>(&mut self.clone())
+            .await?;
+        Ok(res == 1)
+    }
+
+    async fn extend(
+        &self,
+        resource: &str,
+        token: FencingToken,
+        ttl: Duration,
+    ) -> Result<bool, RedisError> {
+        let res = redis::Script::new(EXTEND_SCRIPT)
+            .key(resource)
+            .arg(token.get())
+            .arg(ttl.as_millis() as u64)
+            .invoke_async::<_, i32>(&mut self.clone())
+            .await?;
+        Ok(res == 1)
+    }
+}
+
+/// Held lock, freed by [LockGuard::release].
+///
+/// A guard dropped without being released, by an early return or a panic, frees the lock in a
+/// task spawned on the current runtime. Without a runtime the lock is left to expire.
+pub struct RedisLockGuard<S: LockStore = ConnectionManager> {
+    lock: RedisLock<S>,
+    token: FencingToken,
+    released: bool,
+}
+
+impl<S: LockStore> Debug for RedisLockGuard<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisLockGuard")
+            .field("lock", &self.lock)
+            .field("token", &self.token)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: LockStore> LockGuard for RedisLockGuard<S> {
+    fn fencing_token(&self) -> Option<FencingToken> {
+        Some(self.token)
+    }
+
+    /// Keeps the lock for another `ttl_max`, for critical sections that take longer than planned.
+    async fn extend(&self) -> Result<(), LockError> {
+        let lock = &self.lock;
+        match lock
+            .store
+            .extend(&lock.resource, self.token, lock.ttl_max)
+            .await?
+        {
+            true => Ok(()),
+            false => Err(LockError::Lost),
+        }
+    }
+
+    /// Frees the lock, [LockError::Lost] if it had already expired.
+    async fn release(mut self) -> Result<(), LockError> {
+        self.released = true;
+        let lock = &self.lock;
+        match lock.store.unlock(&lock.resource, self.token).await? {
+            true => Ok(()),
+            false => Err(LockError::Lost),
+        }
+    }
 }
 
-impl<'a> RedisLockGuard<'a> {
-    pub async fn release(mut self) {
-        self.lock.unlock().await.unwrap();
-        self.bomb.defuse();
+impl<S: LockStore> Drop for RedisLockGuard<S> {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let store = self.lock.store.clone();
+        let resource = self.lock.resource.clone();
+        let token = self.token;
+        runtime.spawn(async move {
+            if let Err(err) = store.unlock(&resource, token).await {
+                eprintln!("{resource} not released on drop; err = {err}");
+            }
+        });
     }
 }
 
 impl RedisLock {
-    pub fn new(rconn: ConnectionManager, val: i32, ttl_max: usize) -> RedisLock {
-        let resource: CompactString = repeat_with(fastrand::alphanumeric).take(5).collect();
+    /// Lock on `account`, held for `ttl_max` at most.
+    pub fn new(
+        rconn: ConnectionManager,
+        account: i32,
+        ttl_max: Duration,
+        retry: RetryPolicy,
+    ) -> RedisLock {
+        Self::with_store(rconn, account, ttl_max, retry)
+    }
+}
+
+impl<S: LockStore> RedisLock<S> {
+    pub fn with_store(store: S, account: i32, ttl_max: Duration, retry: RetryPolicy) -> Self {
+        // hash tags keep both keys in the same cluster slot, as the scripts need
+        let resource = compact_str::format_compact!("lock:{{account:{account}}}");
+        let fencing = compact_str::format_compact!("{resource}:fencing");
 
         Self {
-            rconn,
+            store,
             resource,
-            val,
+            fencing,
             ttl_max,
+            retry,
         }
     }
-    pub async fn acquire(&self) -> Result<RedisLockGuard, ()> {
-        let mut retries = 0;
-        loop {
-            if retries > 10 {
-                return Err(());
-            }
 
-            match lock(self.rconn.clone(), &self.resource, self.val, self.ttl_max).await {
-                true => {
-                    return Ok(RedisLockGuard {
-                        lock: self,
-                        bomb: DropBomb::new("RedisLockGuard must be released with release."),
-                    });
-                }
-                false => tokio::task::yield_now().await,
-            };
-            retries += 1;
-        }
+    /// Takes the lock, retrying with backoff while someone else holds it.
+    pub async fn acquire(&self) -> Result<RedisLockGuard<S>, LockError> {
+        let token = self
+            .retry
+            .retry(|| async {
+                let token = self
+                    .store
+                    .try_lock(&self.resource, &self.fencing, self.ttl_max)
+                    .await?;
+                Ok(token)
+            })
+            .await?;
+
+        Ok(RedisLockGuard {
+            lock: self.clone(),
+            token,
+            released: false,
+        })
     }
+}
 
-    async fn unlock(&self) -> Result<bool, ()> {
-        let res = drop_lock(self.rconn.clone(), &self.resource, self.val).await;
-        Ok(res)
+/// [DistributedLock] handing out a [RedisLock] per account.
+#[derive(Clone)]
+pub struct RedisLocks {
+    rconn: ConnectionManager,
+    ttl_max: Duration,
+    retry: RetryPolicy,
+}
+
+impl Debug for RedisLocks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisLocks")
+            .field("ttl_max", &self.ttl_max)
+            .field("retry", &self.retry)
+            .finish_non_exhaustive()
     }
 }
 
-async fn lock(
-    mut redis: impl ConnectionLike + AsyncCommands,
-    resource: &str,
-    val: i32,
-    ttl: usize,
-) -> bool {
-    let options = SetOptions::default()
-        .conditional_set(ExistenceCheck::NX)
-        .with_expiration(SetExpiry::PX(ttl));
-
-    let res = redis
-        .set_options::<_, _, Value>(resource, val, options)
-        .await
-        .unwrap();
-    matches!(res, Value::Okay)
+impl RedisLocks {
+    pub fn new(rconn: ConnectionManager, ttl_max: Duration, retry: RetryPolicy) -> Self {
+        Self {
+            rconn,
+            ttl_max,
+            retry,
+        }
+    }
 }
 
-async fn drop_lock(
-    mut conn: impl ConnectionLike + AsyncCommands,
-    resource: &str,
-    val: i32,
-) -> bool {
-    let script = redis::Script::new(DROP_SCRIPT);
-    let res = script
-        .key(resource)
-        .arg(val)
-        .invoke_async::<_, i32>(&mut conn)
-        .await;
-
-    match res {
-        Ok(val) => val == 1,
-        Err(_) => false,
+impl DistributedLock for RedisLocks {
+    type Guard = RedisLockGuard;
+
+    async fn acquire(&self, account: i32) -> Result<RedisLockGuard, LockError> {
+        RedisLock::new(self.rconn.clone(), account, self.ttl_max, self.retry)
+            .acquire()
+            .await
     }
 }
 
+/// Takes the lock and returns a new fencing token, or 0 when it's taken.
+///
+/// Tokens never fall behind the Redis clock in milliseconds, so they keep growing even if the
+/// counter is lost with the rest of the cache.
+const LOCK_SCRIPT: &str = r#"
+    if redis.call("exists", KEYS[1]) == 1 then
+        return 0
+    end
+    local now = redis.call("time")
+    local floor = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+    local token = redis.call("incr", KEYS[2])
+    if token < floor then
+        token = floor
+        redis.call("set", KEYS[2], token)
+    end
+    redis.call("set", KEYS[1], token, "px", ARGV[1])
+    return token"#;
+
 const DROP_SCRIPT: &str = r#"
     if redis.call("get", KEYS[1]) == ARGV[1] then
         return redis.call("del", KEYS[1])
     else
         return 0
     end"#;
+
+const EXTEND_SCRIPT: &str = r#"
+    if redis.call("get", KEYS[1]) == ARGV[1] then
+        return redis.call("pexpire", KEYS[1], ARGV[2])
+    else
+        return 0
+    end"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::ErrorKind;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::Instant;
+
+    /// Redis as the scripts see it, in memory.
+    #[derive(Debug, Clone, Default)]
+    struct FakeStore {
+        state: Arc<Mutex<FakeState>>,
+    }
+
+    #[derive(Debug, Default)]
+    struct FakeState {
+        locks: HashMap<String, (FencingToken, Instant)>,
+        counters: HashMap<String, i64>,
+        down: bool,
+    }
+
+    impl FakeState {
+        fn held(&mut self, resource: &str) -> Result<Option<FencingToken>, RedisError> {
+            if self.down {
+                return Err(RedisError::from((ErrorKind::IoError, "connection refused")));
+            }
+            self.locks.retain(|_, (_, expiry)| *expiry > Instant::now());
+            Ok(self.locks.get(resource).map(|(token, _)| *token))
+        }
+    }
+
+    impl LockStore for FakeStore {
+        async fn try_lock(
+            &self,
+            resource: &str,
+            fencing: &str,
+            ttl: Duration,
+        ) -> Result<Option<FencingToken>, RedisError> {
+            let mut state = self.state.lock().unwrap();
+            if state.held(resource)?.is_some() {
+                return Ok(None);
+            }
+
+            let counter = state.counters.entry(fencing.into()).or_default();
+            *counter += 1;
+            let token = FencingToken::new(*counter);
+            state
+                .locks
+                .insert(resource.into(), (token, Instant::now() + ttl));
+            Ok(Some(token))
+        }
+
+        async fn unlock(&self, resource: &str, token: FencingToken) -> Result<bool, RedisError> {
+            let mut state = self.state.lock().unwrap();
+            if state.held(resource)? != Some(token) {
+                return Ok(false);
+            }
+            Ok(state.locks.remove(resource).is_some())
+        }
+
+        async fn extend(
+            &self,
+            resource: &str,
+            token: FencingToken,
+            ttl: Duration,
+        ) -> Result<bool, RedisError> {
+            let mut state = self.state.lock().unwrap();
+            if state.held(resource)? != Some(token) {
+                return Ok(false);
+            }
+            state
+                .locks
+                .insert(resource.into(), (token, Instant::now() + ttl));
+            Ok(true)
+        }
+    }
+
+    fn lock(store: &FakeStore, ttl_ms: u64, retry: RetryPolicy) -> RedisLock<FakeStore> {
+        RedisLock::with_store(store.clone(), 1, Duration::from_millis(ttl_ms), retry)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_grow_with_each_acquisition() {
+        let store = FakeStore::default();
+        let lock = lock(&store, 1000, RetryPolicy::default());
+
+        let first = lock.acquire().await.unwrap();
+        let first_token = first.fencing_token();
+        first.release().await.unwrap();
+
+        let second = lock.acquire().await.unwrap();
+        assert!(second.fencing_token() > first_token);
+        second.release().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn contention_and_timeout() {
+        let store = FakeStore::default();
+        let holder = lock(&store, 10_000, RetryPolicy::default());
+        let guard = holder.acquire().await.unwrap();
+
+        let contender = lock(&store, 10_000, RetryPolicy::quick(3, 1000));
+        assert!(matches!(
+            contender.acquire().await,
+            Err(LockError::Contended)
+        ));
+
+        let contender = lock(&store, 10_000, RetryPolicy::quick(u32::MAX, 20));
+        assert!(matches!(contender.acquire().await, Err(LockError::Timeout)));
+
+        guard.release().await.unwrap();
+        let guard = contender.acquire().await.unwrap();
+        guard.release().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_the_holder() {
+        let store = FakeStore::default();
+        let holder = lock(&store, 10_000, RetryPolicy::default());
+        let guard = holder.acquire().await.unwrap();
+
+        let contender = lock(&store, 10_000, RetryPolicy::quick(u32::MAX, 1000));
+        let waiting = tokio::spawn(async move {
+            let guard = contender.acquire().await.unwrap();
+            let token = guard.fencing_token();
+            guard.release().await.unwrap();
+            token
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let token = guard.fencing_token();
+        guard.release().await.unwrap();
+        assert!(waiting.await.unwrap() > token);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_lock_is_lost() {
+        let store = FakeStore::default();
+        let slow = lock(&store, 5, RetryPolicy::default());
+        let stale = slow.acquire().await.unwrap();
+        stale.extend().await.unwrap();
+
+        tokio::time::advance(Duration::from_millis(10)).await;
+        let other = lock(&store, 1000, RetryPolicy::default());
+        let current = other.acquire().await.unwrap();
+        assert!(current.fencing_token() > stale.fencing_token());
+
+        assert!(matches!(stale.extend().await, Err(LockError::Lost)));
+        assert!(matches!(stale.release().await, Err(LockError::Lost)));
+        current.release().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn redis_failures_are_reported() {
+        let store = FakeStore::default();
+        store.state.lock().unwrap().down = true;
+
+        let lock = lock(&store, 1000, RetryPolicy::default());
+        assert!(matches!(lock.acquire().await, Err(LockError::Redis(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_guard_unlocks() {
+        let store = FakeStore::default();
+        let lock = lock(&store, 10_000, RetryPolicy::quick(u32::MAX, 1000));
+
+        let token = {
+            let guard = lock.acquire().await.unwrap();
+            guard.fencing_token()
+        };
+
+        let guard = lock.acquire().await.unwrap();
+        assert!(guard.fencing_token() > token);
+        guard.release().await.unwrap();
+    }
+
+    #[test]
+    fn dropped_guard_without_runtime_expires() {
+        let store = FakeStore::default();
+        let lock = lock(&store, 20, RetryPolicy::quick(u32::MAX, 1000));
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let guard = runtime.block_on(lock.acquire()).unwrap();
+        drop(guard);
+        assert!(store
+            .state
+            .lock()
+            .unwrap()
+            .held(&lock.resource)
+            .unwrap()
+            .is_some());
+
+        let guard = runtime.block_on(lock.acquire()).unwrap();
+        runtime.block_on(guard.release()).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+//! Decoder for `Transfer-Encoding: chunked` request bodies.
+
+use crate::infrastructure::server_impl::errors::HttpError;
+use httparse::Status;
+
+const MAX_TRAILERS: usize = 16;
+
+/// Progress through a chunked body that hasn't fully arrived yet.
+///
+/// Chunks decoded by one call aren't parsed again by the next, as long as `buf` keeps starting at
+/// the same body and only grows at the end, which is how the connection read buffer behaves.
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    body: Vec<u8>,
+    /// Bytes of the encoded body already decoded into `body`, always at a chunk boundary.
+    pos: usize,
+}
+
+impl ChunkedDecoder {
+    /// Decodes the chunked body at the start of `buf` into a contiguous buffer.
+    ///
+    /// Returns `None` while the last chunk or the trailer section haven't arrived yet, otherwise
+    /// the decoded body alongside the number of bytes the encoded body occupies in `buf`, and the
+    /// decoder is ready for the next body. Trailer fields are validated and then discarded,
+    /// nothing downstream cares about them.
+    pub fn decode(
+        &mut self,
+        buf: &[u8],
+        max_body_size: usize,
+    ) -> Result<Option<(Vec<u8>, usize)>, HttpError> {
+        let decoded = self.resume(buf, max_body_size);
+        if !matches!(decoded, Ok(None)) {
+            *self = Self::default();
+        }
+        decoded
+    }
+
+    fn resume(
+        &mut self,
+        buf: &[u8],
+        max_body_size: usize,
+    ) -> Result<Option<(Vec<u8>, usize)>, HttpError> {
+        let mut pos = self.pos;
+
+        loop {
+            let (size_len, size) = match httparse::parse_chunk_size(&buf[pos..]) {
+                Ok(Status::Complete(parsed)) => parsed,
+                Ok(Status::Partial) => return Ok(None),
+                Err(_) => return Err(HttpError::BadRequest("Invalid chunk size.")),
+            };
+            pos += size_len;
+
+            if size == 0 {
+                break;
+            }
+
+            let Some(size) = usize::try_from(size)
+                .ok()
+                .filter(|size| self.body.len() + size <= max_body_size)
+            else {
+                return Err(HttpError::ContentTooLarge);
+            };
+
+            let chunk_end = pos + size;
+            if buf.len() < chunk_end + 2 {
+                return Ok(None);
+            }
+            if &buf[chunk_end..chunk_end + 2] != b"\r\n" {
+                return Err(HttpError::BadRequest("Chunk not terminated by CRLF."));
+            }
+
+            self.body.extend_from_slice(&buf[pos..chunk_end]);
+            pos = chunk_end + 2;
+            self.pos = pos;
+        }
+
+        let mut trailers = [httparse::EMPTY_HEADER; MAX_TRAILERS];
+        match httparse::parse_headers(&buf[pos..], &mut trailers) {
+            Ok(Status::Complete((trailers_len, _))) => {
+                Ok(Some((std::mem::take(&mut self.body), pos + trailers_len)))
+            }
+            Ok(Status::Partial) => Ok(None),
+            Err(_) => Err(HttpError::BadRequest("Invalid trailer section.")),
+        }
+    }
+}
+
+/// Decodes a chunked body in one go, see [ChunkedDecoder::decode].
+pub fn decode_chunked(
+    buf: &[u8],
+    max_body_size: usize,
+) -> Result<Option<(Vec<u8>, usize)>, HttpError> {
+    ChunkedDecoder::default().decode(buf, max_body_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn success_multiple_chunks_with_trailers() {
+        let sample =
+            b"4\r\n{\"va\r\n6;ext=1\r\nlor\":1\r\n1\r\n}\r\n0\r\nExpires: never\r\n\r\nGET";
+
+        let (body, consumed) = decode_chunked(sample, 1024).unwrap().unwrap();
+        assert_eq!(body, b"{\"valor\":1}");
+        assert_eq!(&sample[consumed..], b"GET");
+    }
+
+    #[test]
+    fn partial_until_trailers_end() {
+        let sample = b"2\r\n{}\r\n0\r\n\r\n";
+
+        for end in 0..sample.len() {
+            assert!(decode_chunked(&sample[..end], 1024).unwrap().is_none());
+        }
+        let (body, consumed) = decode_chunked(sample, 1024).unwrap().unwrap();
+        assert_eq!(body, b"{}");
+        assert_eq!(consumed, sample.len());
+    }
+
+    #[test]
+    fn resumes_after_decoded_chunks() {
+        let sample = b"2\r\n{}\r\n3\r\nabc\r\n0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::default();
+
+        assert!(decoder.decode(&sample[..12], 1024).unwrap().is_none());
+        assert_eq!(decoder.body, b"{}");
+        assert_eq!(decoder.pos, 7);
+
+        // bytes already decoded aren't looked at again
+        let mut tampered = sample.to_vec();
+        tampered[..7].fill(b'!');
+        let (body, consumed) = decoder.decode(&tampered, 1024).unwrap().unwrap();
+        assert_eq!(body, b"{}abc");
+        assert_eq!(consumed, sample.len());
+        assert_eq!(decoder.pos, 0);
+    }
+
+    #[test]
+    fn failure_malformed_framing() {
+        assert!(decode_chunked(b"zz\r\n{}\r\n0\r\n\r\n", 1024).is_err());
+        assert!(decode_chunked(b"2\r\n{}}\r\n0\r\n\r\n", 1024).is_err());
+    }
+
+    #[test]
+    fn failure_body_too_large() {
+        assert!(decode_chunked(b"5\r\n", 4).is_err());
+        assert!(decode_chunked(b"3\r\nabc\r\n3\r\n", 4).is_err());
+    }
+}
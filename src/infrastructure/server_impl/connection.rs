@@ -0,0 +1,176 @@
+use crate::application::ServerData;
+use crate::infrastructure::server_impl::chunked::ChunkedDecoder;
+use crate::infrastructure::server_impl::errors::HttpError;
+use crate::infrastructure::server_impl::response::Response;
+use crate::infrastructure::server_impl::server::{match_routes, parse_http_with, MAX_BODY_SIZE};
+use bytes::{Buf, Bytes, BytesMut};
+use std::io;
+use std::io::IoSlice;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// Initial capacity of the per-connection read and write buffers, messages are not that long
+/// anyway.
+const READ_BUFFER_CAPACITY: usize = 1024;
+const WRITE_BUFFER_CAPACITY: usize = 1024;
+
+/// Room left in the read buffer for the request line and headers, on top of the body.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ConnectionConfig {
+    /// Biggest body accepted, either through `Content-Length` or once chunks are decoded.
+    pub max_body_size: usize,
+    /// How long to wait for the next request before closing the connection.
+    pub idle_timeout: Duration,
+    /// How long a request may take to arrive once its first bytes did, however steadily they
+    /// trickle in.
+    pub request_timeout: Duration,
+    /// Requests served before the connection is closed, the last response announces it.
+    pub max_requests: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_body_size: MAX_BODY_SIZE,
+            idle_timeout: Duration::from_secs(30),
+            request_timeout: Duration::from_secs(10),
+            max_requests: 10_000,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Defaults overridden by `MAX_BODY_SIZE`, `IDLE_TIMEOUT_SECS`, `REQUEST_TIMEOUT_SECS` and
+    /// `MAX_REQUESTS`.
+    pub fn from_env() -> Self {
+        fn var<T: FromStr>(key: &str) -> Option<T> {
+            std::env::var(key).ok().and_then(|val| val.parse().ok())
+        }
+
+        let default = Self::default();
+        Self {
+            max_body_size: var("MAX_BODY_SIZE").unwrap_or(default.max_body_size),
+            idle_timeout: var("IDLE_TIMEOUT_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.idle_timeout),
+            request_timeout: var("REQUEST_TIMEOUT_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.request_timeout),
+            max_requests: var("MAX_REQUESTS").unwrap_or(default.max_requests),
+        }
+    }
+}
+
+/// Serves requests sent through `stream` until either side decides to close the connection.
+///
+/// Bytes are accumulated in a buffer owned by the connection, so requests split across several TCP
+/// segments are only handled once complete, and pipelined requests are handled one after another
+/// without leaking into each other.
+pub async fn handle_connection(mut stream: TcpStream, data: ServerData, config: ConnectionConfig) {
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_CAPACITY);
+    let mut write_buf = BytesMut::with_capacity(WRITE_BUFFER_CAPACITY);
+    // chunked bodies carry framing overhead, anything bigger than this can't fit the limit anyway
+    let max_request_size = MAX_HEAD_SIZE + 2 * config.max_body_size;
+    let mut served = 0;
+    let mut chunked = ChunkedDecoder::default();
+    // set once the first bytes of a request arrive, reading more of it doesn't push it back
+    let mut request_deadline = None;
+
+    loop {
+        while !buf.is_empty() {
+            let (request, consumed) =
+                match parse_http_with(&buf, config.max_body_size, &mut chunked) {
+                    Ok(Some(parsed)) => parsed,
+                    Ok(None) => break,
+                    Err(e) => {
+                        // framing is lost at this point, so there is no next request to look for
+                        close_with(&mut stream, e).await;
+                        return;
+                    }
+                };
+
+            served += 1;
+            let keep_alive = request.keep_alive() && served < config.max_requests;
+            let method = request.method;
+
+            let response = match_routes(&data, request)
+                .await
+                .unwrap_or_else(Response::from);
+            let body = response.write_http(&mut write_buf, keep_alive, method);
+            if let Err(e) = write_all_vectored(&mut stream, &mut write_buf, body).await {
+                eprintln!("failed to write to socket; err = {:?}", e);
+                return;
+            }
+            buf.advance(consumed);
+            request_deadline = None;
+
+            if !keep_alive {
+                let _ = stream.shutdown().await;
+                return;
+            }
+        }
+
+        if buf.len() >= max_request_size {
+            close_with(&mut stream, HttpError::ContentTooLarge).await;
+            return;
+        }
+
+        let deadline = if buf.is_empty() {
+            Instant::now() + config.idle_timeout
+        } else {
+            *request_deadline.get_or_insert_with(|| Instant::now() + config.request_timeout)
+        };
+        let read = tokio::time::timeout_at(deadline, stream.read_buf(&mut buf)).await;
+        match read {
+            // idle between requests, nothing to answer
+            Err(_) if buf.is_empty() => {
+                let _ = stream.shutdown().await;
+                return;
+            }
+            Err(_) => {
+                close_with(&mut stream, HttpError::RequestTimeout).await;
+                return;
+            }
+            Ok(Ok(0)) => return,
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                eprintln!("failed to read from socket; err = {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Writes `head` followed by `body` with as few syscalls as possible, leaving `head` empty so its
+/// capacity gets reused by the next response.
+async fn write_all_vectored(
+    stream: &mut TcpStream,
+    head: &mut BytesMut,
+    mut body: Bytes,
+) -> io::Result<()> {
+    while head.has_remaining() || body.has_remaining() {
+        let slices = [IoSlice::new(head), IoSlice::new(&body)];
+        let written = stream.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+
+        let from_head = written.min(head.len());
+        head.advance(from_head);
+        body.advance(written - from_head);
+    }
+
+    Ok(())
+}
+
+async fn close_with(stream: &mut TcpStream, error: HttpError) {
+    let _ = stream
+        .write_all(&Response::from(error).into_http(false))
+        .await;
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,202 @@
+//! Errors raised while serving a request, each one maps to a single HTTP status.
+
+use crate::application::adapters::validation::{FieldError, PayloadError};
+use crate::domain::errors::{AccountError, ReversalError, TransactionError};
+use crate::infrastructure::lock::LockError;
+use crate::infrastructure::server_impl::response::{Body, Response, StatusCode};
+use crate::infrastructure::server_impl::server::Header;
+use compact_str::CompactString;
+use serde::Serialize;
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// The request couldn't be framed or parsed.
+    BadRequest(&'static str),
+    NotFound(&'static str),
+    /// Holds the `Allow` header value listing the methods the resource supports.
+    MethodNotAllowed(CompactString),
+    /// The client took too long to send a complete request.
+    RequestTimeout,
+    /// Body bigger than the configured limit.
+    ContentTooLarge,
+    /// Well-formed request the domain refused to handle.
+    UnprocessableEntity(&'static str),
+    /// Well-formed body with fields that don't validate.
+    InvalidFields(Vec<FieldError>),
+    /// Postgres, Redis or anything else we depend on failed.
+    ServiceUnavailable(eyre::Report),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    #[serde(rename = "erro")]
+    error: &'static str,
+    #[serde(rename = "campos")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<FieldError>,
+}
+
+impl HttpError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest(_) => StatusCode::BadRequest,
+            HttpError::NotFound(_) => StatusCode::NotFound,
+            HttpError::MethodNotAllowed(_) => StatusCode::MethodNotAllowed,
+            HttpError::RequestTimeout => StatusCode::RequestTimeout,
+            HttpError::ContentTooLarge => StatusCode::ContentTooLarge,
+            HttpError::UnprocessableEntity(_) | HttpError::InvalidFields(_) => {
+                StatusCode::UnprocessableEntity
+            }
+            HttpError::ServiceUnavailable(_) => StatusCode::ServiceUnavailable,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            HttpError::BadRequest(msg)
+            | HttpError::NotFound(msg)
+            | HttpError::UnprocessableEntity(msg) => msg,
+            HttpError::InvalidFields(_) => "Invalid fields.",
+            HttpError::MethodNotAllowed(_) => "Method not allowed.",
+            HttpError::RequestTimeout => "Request timeout.",
+            HttpError::ContentTooLarge => "Body too large.",
+            // details stay in our logs
+            HttpError::ServiceUnavailable(_) => "Service unavailable.",
+        }
+    }
+}
+
+impl From<HttpError> for Response {
+    fn from(value: HttpError) -> Self {
+        if let HttpError::ServiceUnavailable(report) = &value {
+            eprintln!("infrastructure failure; err = {:?}", report);
+        }
+
+        let (status_code, error) = (value.status_code(), value.message());
+        let (fields, allowed) = match value {
+            HttpError::InvalidFields(fields) => (fields, None),
+            HttpError::MethodNotAllowed(allowed) => (Vec::new(), Some(allowed)),
+            _ => (Vec::new(), None),
+        };
+
+        let response =
+            Response::from_status_code(status_code, Body::json(ErrorBody { error, fields }));
+        match allowed {
+            Some(allowed) => response.with_header(Header::ALLOW, allowed),
+            None => response,
+        }
+    }
+}
+
+impl From<AccountError> for HttpError {
+    fn from(value: AccountError) -> Self {
+        match value {
+            AccountError::InsufficientCredit => {
+                HttpError::UnprocessableEntity("Insufficient credit.")
+            }
+            AccountError::NotFound => HttpError::NotFound("Account not found."),
+            AccountError::LimitBelowBalance => {
+                HttpError::UnprocessableEntity("Balance exceeds the new limit.")
+            }
+            AccountError::NonZeroBalance => {
+                HttpError::UnprocessableEntity("Account balance isn't zero.")
+            }
+            AccountError::BalanceOverflow => {
+                HttpError::UnprocessableEntity("Balance out of range.")
+            }
+            AccountError::Closed => HttpError::UnprocessableEntity("Account is closed."),
+        }
+    }
+}
+
+impl From<TransactionError> for HttpError {
+    fn from(value: TransactionError) -> Self {
+        match value {
+            TransactionError::InvalidDescription => {
+                HttpError::UnprocessableEntity("Invalid description.")
+            }
+            TransactionError::InvalidKind => HttpError::UnprocessableEntity("Invalid kind."),
+            TransactionError::InvalidAmount => HttpError::UnprocessableEntity("Invalid amount."),
+            TransactionError::NotFound => HttpError::NotFound("Transaction not found."),
+            TransactionError::AlreadyReversed => {
+                HttpError::UnprocessableEntity("Transaction already reversed.")
+            }
+            TransactionError::NotReversible => {
+                HttpError::UnprocessableEntity("Transaction can't be reversed.")
+            }
+            TransactionError::IdempotencyKeyReused => {
+                HttpError::UnprocessableEntity("Idempotency-Key used for another transaction.")
+            }
+        }
+    }
+}
+
+impl From<ReversalError> for HttpError {
+    fn from(value: ReversalError) -> Self {
+        match value {
+            ReversalError::Account(err) => err.into(),
+            ReversalError::Transaction(err) => err.into(),
+        }
+    }
+}
+
+impl From<PayloadError> for HttpError {
+    fn from(value: PayloadError) -> Self {
+        match value {
+            PayloadError::Malformed => HttpError::BadRequest("Body isn't valid JSON."),
+            PayloadError::Invalid(fields) => HttpError::InvalidFields(fields),
+        }
+    }
+}
+
+/// Contended or not, a lock we can't get is ours to fix, the client may retry later.
+impl From<LockError> for HttpError {
+    fn from(value: LockError) -> Self {
+        HttpError::ServiceUnavailable(value.into())
+    }
+}
+
+impl From<eyre::Report> for HttpError {
+    fn from(value: eyre::Report) -> Self {
+        HttpError::ServiceUnavailable(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_json_error_body() {
+        let response = Response::from(HttpError::from(AccountError::InsufficientCredit));
+        assert_eq!(response.status_code, StatusCode::UnprocessableEntity);
+        assert!(response
+            .into_http(true)
+            .ends_with(br#"{"erro":"Insufficient credit."}"#));
+
+        let response = Response::from(HttpError::ServiceUnavailable(eyre::eyre!("redis down")));
+        assert_eq!(response.status_code, StatusCode::ServiceUnavailable);
+        assert!(response
+            .into_http(true)
+            .ends_with(br#"{"erro":"Service unavailable."}"#));
+
+        let response = Response::from(HttpError::InvalidFields(vec![FieldError {
+            field: "valor".into(),
+            error: "Required.",
+        }]));
+        assert_eq!(response.status_code, StatusCode::UnprocessableEntity);
+        assert!(response.into_http(true).ends_with(
+            br#"{"erro":"Invalid fields.","campos":[{"campo":"valor","erro":"Required."}]}"#
+        ));
+
+        let response = Response::from(HttpError::MethodNotAllowed("GET, POST".into()));
+        assert_eq!(response.status_code, StatusCode::MethodNotAllowed);
+        assert_eq!(
+            response
+                .headers
+                .get(&Header::ALLOW.into())
+                .map(|v| v.as_str()),
+            Some("GET, POST")
+        );
+    }
+}
@@ -1,3 +1,8 @@
+pub mod chunked;
+pub mod connection;
+pub mod errors;
+pub mod query;
 pub mod request;
 pub mod response;
+pub mod router;
 pub mod server;
@@ -0,0 +1,136 @@
+//! `application/x-www-form-urlencoded` query strings, decoded lazily and without copying unless
+//! a value actually holds escapes.
+
+use crate::infrastructure::server_impl::errors::HttpError;
+use std::borrow::Cow;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Query<'a> {
+    raw: &'a str,
+}
+
+impl<'a> Query<'a> {
+    pub fn new(raw: &'a str) -> Self {
+        Self { raw }
+    }
+
+    /// Decoded `(key, value)` pairs in the order they were sent, keys without `=` get an empty
+    /// value.
+    pub fn pairs(&self) -> impl Iterator<Item = (Cow<'a, str>, Cow<'a, str>)> {
+        self.raw
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, val) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(key), percent_decode(val))
+            })
+    }
+
+    /// First value sent for `key`.
+    pub fn get(&self, key: &str) -> Option<Cow<'a, str>> {
+        self.pairs().find(|(k, _)| k == key).map(|(_, val)| val)
+    }
+
+    /// Every value sent for `key`, e.g. `?tipo=c&tipo=d`.
+    pub fn get_all<'k>(&self, key: &'k str) -> impl Iterator<Item = Cow<'a, str>> + 'k
+    where
+        'a: 'k,
+    {
+        self.pairs()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, val)| val)
+    }
+
+    /// Parses the value of `key`, a value that can't be parsed is the client's fault.
+    pub fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, HttpError> {
+        self.parse_with(key, |val| T::from_str(val).ok())
+    }
+
+    /// Same as [Query::parse], for types that don't implement [FromStr] the way we need.
+    pub fn parse_with<T>(
+        &self,
+        key: &str,
+        parse: impl FnOnce(&str) -> Option<T>,
+    ) -> Result<Option<T>, HttpError> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(val) => parse(&val)
+                .map(Some)
+                .ok_or(HttpError::BadRequest("Invalid query parameter.")),
+        }
+    }
+}
+
+/// Decodes `%XX` escapes and `+` as space.
+///
+/// Borrows `input` when there's nothing to decode. Malformed escapes are kept as they were sent
+/// and invalid UTF-8 is replaced, the same way browsers treat them.
+pub fn percent_decode(input: &str) -> Cow<'_, str> {
+    if !input.bytes().any(|c| c == b'%' || c == b'+') {
+        return Cow::Borrowed(input);
+    }
+
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => decoded.push(b' '),
+            b'%' => match bytes.get(idx + 1..idx + 3).and_then(decode_hex_pair) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    idx += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            c => decoded.push(c),
+        }
+        idx += 1;
+    }
+
+    match String::from_utf8(decoded) {
+        Ok(decoded) => Cow::Owned(decoded),
+        Err(e) => Cow::Owned(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+    }
+}
+
+fn decode_hex_pair(pair: &[u8]) -> Option<u8> {
+    let hex = |c: u8| (c as char).to_digit(16);
+    Some((hex(pair[0])? * 16 + hex(pair[1])?) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn success_decoding() {
+        assert!(matches!(percent_decode("plain"), Cow::Borrowed("plain")));
+        assert_eq!(percent_decode("a+b%20c"), "a b c");
+        assert_eq!(percent_decode("%C3%A7%c3%a3o"), "ção");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn success_typed_accessors() {
+        let query = Query::new("limit=50&&flag&from=2024-01-01T00%3A00%3A00Z&tipo=c&tipo=d");
+
+        assert_eq!(query.parse::<u32>("limit").unwrap(), Some(50));
+        assert_eq!(query.parse::<u32>("missing").unwrap(), None);
+        assert_eq!(query.get("flag").as_deref(), Some(""));
+        assert_eq!(query.get("from").as_deref(), Some("2024-01-01T00:00:00Z"));
+        assert_eq!(query.get_all("tipo").collect::<Vec<_>>(), ["c", "d"]);
+    }
+
+    #[test]
+    fn failure_unparseable_value() {
+        let query = Query::new("limit=-1");
+        assert!(matches!(
+            query.parse::<u32>("limit"),
+            Err(HttpError::BadRequest(_))
+        ));
+    }
+}
@@ -1,10 +1,41 @@
-use crate::infrastructure::server_impl::server::{Header, Method};
+use crate::infrastructure::server_impl::query::Query;
+use crate::infrastructure::server_impl::router::strip_query;
+use crate::infrastructure::server_impl::server::{Header, Method, Version};
 use enum_map::EnumMap;
+use std::borrow::Cow;
 
 #[derive(Debug)]
 pub struct Request<'a> {
     pub method: Method,
+    pub version: Version,
     pub headers: EnumMap<Header, &'a str>,
     pub resource: &'a str,
-    pub body: Option<&'a [u8]>,
+    pub body: Option<Cow<'a, [u8]>>,
+}
+
+impl<'a> Request<'a> {
+    /// Resource without its query string.
+    pub fn path(&self) -> &'a str {
+        strip_query(self.resource)
+    }
+
+    pub fn query(&self) -> Query<'a> {
+        self.resource
+            .split_once('?')
+            .map(|(_, query)| Query::new(query))
+            .unwrap_or_default()
+    }
+
+    /// Whether the client expects the connection to stay open after this request, see RFC 9112
+    /// section 9.3.
+    pub fn keep_alive(&self) -> bool {
+        let mut options = self.headers[Header::CONNECTION]
+            .split(',')
+            .map(|option| option.trim());
+
+        match self.version {
+            Version::Http10 => options.any(|option| unicase::eq(option, "keep-alive")),
+            Version::Http11 => !options.any(|option| unicase::eq(option, "close")),
+        }
+    }
 }
@@ -1,68 +1,347 @@
-use crate::infrastructure::server_impl::server::Header;
-use bytes::Bytes;
+use crate::infrastructure::server_impl::server::{Header, HeaderName, Method};
+use bytes::{BufMut, Bytes, BytesMut};
 use compact_str::CompactString;
 use derive_more::Deref;
 use fnv::FnvHashMap;
 use serde::Serialize;
-use std::fmt::Write;
-use strum::{EnumMessage, EnumString, IntoStaticStr};
+use std::fmt::{Debug, Write};
+use std::str::FromStr;
+use strum::{EnumIter, EnumMessage, EnumString, IntoStaticStr};
 
+/// Status codes from RFC 9110, plus the ones RFC 6585 added on top.
 #[allow(clippy::upper_case_acronyms, non_camel_case_types)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, IntoStaticStr, EnumString, EnumMessage)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, IntoStaticStr, EnumString, EnumMessage, EnumIter,
+)]
 pub enum StatusCode {
+    #[strum(serialize = "100", message = "Continue")]
+    Continue,
+    #[strum(serialize = "101", message = "Switching Protocols")]
+    SwitchingProtocols,
+
     #[strum(serialize = "200", message = "OK")]
     Ok,
-    #[strum(serialize = "404", message = "Not Found.")]
+    #[strum(serialize = "201", message = "Created")]
+    Created,
+    #[strum(serialize = "202", message = "Accepted")]
+    Accepted,
+    #[strum(serialize = "203", message = "Non-Authoritative Information")]
+    NonAuthoritativeInformation,
+    #[strum(serialize = "204", message = "No Content")]
+    NoContent,
+    #[strum(serialize = "205", message = "Reset Content")]
+    ResetContent,
+    #[strum(serialize = "206", message = "Partial Content")]
+    PartialContent,
+
+    #[strum(serialize = "300", message = "Multiple Choices")]
+    MultipleChoices,
+    #[strum(serialize = "301", message = "Moved Permanently")]
+    MovedPermanently,
+    #[strum(serialize = "302", message = "Found")]
+    Found,
+    #[strum(serialize = "303", message = "See Other")]
+    SeeOther,
+    #[strum(serialize = "304", message = "Not Modified")]
+    NotModified,
+    #[strum(serialize = "305", message = "Use Proxy")]
+    UseProxy,
+    #[strum(serialize = "307", message = "Temporary Redirect")]
+    TemporaryRedirect,
+    #[strum(serialize = "308", message = "Permanent Redirect")]
+    PermanentRedirect,
+
+    #[strum(serialize = "400", message = "Bad Request")]
+    BadRequest,
+    #[strum(serialize = "401", message = "Unauthorized")]
+    Unauthorized,
+    #[strum(serialize = "402", message = "Payment Required")]
+    PaymentRequired,
+    #[strum(serialize = "403", message = "Forbidden")]
+    Forbidden,
+    #[strum(serialize = "404", message = "Not Found")]
     NotFound,
-    #[strum(serialize = "422", message = "Stop with this shit.")]
+    #[strum(serialize = "405", message = "Method Not Allowed")]
+    MethodNotAllowed,
+    #[strum(serialize = "406", message = "Not Acceptable")]
+    NotAcceptable,
+    #[strum(serialize = "407", message = "Proxy Authentication Required")]
+    ProxyAuthenticationRequired,
+    #[strum(serialize = "408", message = "Request Timeout")]
+    RequestTimeout,
+    #[strum(serialize = "409", message = "Conflict")]
+    Conflict,
+    #[strum(serialize = "410", message = "Gone")]
+    Gone,
+    #[strum(serialize = "411", message = "Length Required")]
+    LengthRequired,
+    #[strum(serialize = "412", message = "Precondition Failed")]
+    PreconditionFailed,
+    #[strum(serialize = "413", message = "Content Too Large")]
+    ContentTooLarge,
+    #[strum(serialize = "414", message = "URI Too Long")]
+    UriTooLong,
+    #[strum(serialize = "415", message = "Unsupported Media Type")]
+    UnsupportedMediaType,
+    #[strum(serialize = "416", message = "Range Not Satisfiable")]
+    RangeNotSatisfiable,
+    #[strum(serialize = "417", message = "Expectation Failed")]
+    ExpectationFailed,
+    #[strum(serialize = "421", message = "Misdirected Request")]
+    MisdirectedRequest,
+    #[strum(serialize = "422", message = "Unprocessable Content")]
     UnprocessableEntity,
+    #[strum(serialize = "426", message = "Upgrade Required")]
+    UpgradeRequired,
+    #[strum(serialize = "428", message = "Precondition Required")]
+    PreconditionRequired,
+    #[strum(serialize = "429", message = "Too Many Requests")]
+    TooManyRequests,
+    #[strum(serialize = "431", message = "Request Header Fields Too Large")]
+    RequestHeaderFieldsTooLarge,
+
+    #[strum(serialize = "500", message = "Internal Server Error")]
+    InternalServerError,
+    #[strum(serialize = "501", message = "Not Implemented")]
+    NotImplemented,
+    #[strum(serialize = "502", message = "Bad Gateway")]
+    BadGateway,
+    #[strum(serialize = "503", message = "Service Unavailable")]
+    ServiceUnavailable,
+    #[strum(serialize = "504", message = "Gateway Timeout")]
+    GatewayTimeout,
+    #[strum(serialize = "505", message = "HTTP Version Not Supported")]
+    HttpVersionNotSupported,
+    #[strum(serialize = "511", message = "Network Authentication Required")]
+    NetworkAuthenticationRequired,
+}
+
+impl StatusCode {
+    pub fn as_u16(self) -> u16 {
+        let code: &str = self.into();
+        u16::from_str(code).expect("Every variant is serialized as a number.")
+    }
+
+    pub fn reason_phrase(self) -> &'static str {
+        self.get_message().expect("Every variant has a message.")
+    }
+
+    /// Informational, 204 and 304 responses never carry a body, not even an empty one.
+    fn allows_body(self) -> bool {
+        !matches!(self.as_u16(), 100..=199 | 204 | 304)
+    }
+}
+
+impl TryFrom<http::StatusCode> for StatusCode {
+    type Error = http::StatusCode;
+
+    /// Fails for the codes we don't know about, handing the original back.
+    fn try_from(value: http::StatusCode) -> Result<Self, Self::Error> {
+        StatusCode::from_str(value.as_str()).map_err(|_| value)
+    }
+}
+
+impl From<StatusCode> for http::StatusCode {
+    fn from(value: StatusCode) -> Self {
+        http::StatusCode::from_u16(value.as_u16()).expect("Every variant is in the valid range.")
+    }
+}
+
+/// Digits reserved for a `Content-Length` only known once the body is serialized.
+const CONTENT_LENGTH_WIDTH: usize = 10;
+
+#[derive(Debug)]
+pub enum Body {
+    /// Already serialized, handed to the socket as is.
+    Bytes(Bytes),
+    /// Serialized straight into the connection write buffer.
+    Json(Box<dyn JsonBody>),
+}
+
+impl Body {
+    pub fn json<T>(value: T) -> Self
+    where
+        T: Serialize + Debug + Send + 'static,
+    {
+        Body::Json(Box::new(value))
+    }
+}
+
+impl From<String> for Body {
+    fn from(value: String) -> Self {
+        Body::Bytes(value.into())
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(value: &'static str) -> Self {
+        Body::Bytes(Bytes::from_static(value.as_bytes()))
+    }
+}
+
+pub trait JsonBody: Debug + Send {
+    fn write_json(&self, buf: &mut BytesMut) -> simd_json::Result<()>;
+}
+
+impl<T> JsonBody for T
+where
+    T: Serialize + Debug + Send,
+{
+    fn write_json(&self, buf: &mut BytesMut) -> simd_json::Result<()> {
+        simd_json::to_writer(BytesWriter(buf), self)
+    }
+}
+
+/// [bytes::buf::Writer] goes through the generic `BufMut::put`, which is too slow for the tiny
+/// writes a serializer does.
+struct BytesWriter<'a>(&'a mut BytesMut);
+
+impl std::io::Write for BytesWriter<'_> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.0.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct Response {
-    pub headers: FnvHashMap<Header, CompactString>,
+    pub headers: FnvHashMap<HeaderName, CompactString>,
     pub status_code: StatusCode,
-    pub body: Option<String>,
+    pub body: Option<Body>,
 }
 
 impl Response {
-    pub fn from_status_code(value: StatusCode, body: impl Into<Option<String>>) -> Self {
+    pub fn from_status_code(value: StatusCode, body: impl Into<Option<Body>>) -> Self {
         Self {
             headers: Default::default(),
             status_code: value,
             body: body.into(),
         }
     }
+
+    pub fn with_header(
+        mut self,
+        name: impl Into<HeaderName>,
+        value: impl Into<CompactString>,
+    ) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+}
+
+/// Token characters from RFC 9110 section 5.6.2.
+fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+}
+
+/// Anything that could end the header line early is refused, visible ASCII, spaces and tabs
+/// are fine.
+fn is_valid_header_value(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|c| c == b'\t' || (b' '..=b'~').contains(&c))
 }
 
 impl Response {
-    pub fn into_http(self) -> Bytes {
-        // FIXME: use BufWriter to avoid calling write multiple times
-        let mut buf = String::with_capacity(80);
+    /// Serializes the whole response to a request other than `HEAD` into a fresh buffer, see
+    /// [Response::write_http].
+    pub fn into_http(self, keep_alive: bool) -> Bytes {
+        let mut buf = BytesMut::with_capacity(128);
+        let body = self.write_http(&mut buf, keep_alive, Method::GET);
+        buf.extend_from_slice(&body);
+        buf.freeze()
+    }
+
+    /// Serializes the response to a `method` request into `buf`, announcing whether the
+    /// connection stays open afterwards.
+    ///
+    /// JSON bodies are serialized in place right after the header block, with `Content-Length`
+    /// patched in afterwards. Bodies that are already [Body::Bytes] are not copied, they are
+    /// returned instead and must be written right after `buf`.
+    ///
+    /// The header block is always terminated and the body always delimited, so the peer can find
+    /// where the next response starts on a persistent connection. Responses to `HEAD` and those
+    /// whose status has no body are written without one, whatever the handler set.
+    pub fn write_http(self, buf: &mut BytesMut, keep_alive: bool, method: Method) -> Bytes {
         let status_code: &str = self.status_code.into();
-        let status_message = self.status_code.get_message().unwrap();
+        let status_message = self.status_code.reason_phrase();
+        let connection = if keep_alive { "keep-alive" } else { "close" };
 
         write!(
             buf,
             "HTTP/1.1 {status_code} {status_message}\r\n\
-             Server: localhost\r\n"
+             Server: localhost\r\n\
+             Connection: {connection}\r\n"
         )
         .expect("No reason to fail.");
 
-        if let Some(body) = self.body {
-            let length = body.len();
-            write!(
-                buf,
-                "Connection: keep-alive\r\n\
-                 Content-Type: application/json; charset-utf-8\r\n\
-                 Content-Length: {length}\r\n\r\n{body}"
-            )
-            .unwrap();
-        } else {
-            write!(buf, "Connection: close\r\n").unwrap();
+        for (name, value) in &self.headers {
+            // framing belongs to us, handlers don't get to pick it
+            if let HeaderName::Known(
+                Header::CONNECTION | Header::CONTENT_LENGTH | Header::TRANSFER_ENCODING,
+            ) = name
+            {
+                continue;
+            }
+            if !is_valid_header_name(name.as_str()) || !is_valid_header_value(value) {
+                eprintln!("refusing to write invalid header {:?}", name);
+                continue;
+            }
+
+            write!(buf, "{}: {value}\r\n", name.as_str()).expect("No reason to fail.");
         }
 
-        buf.into()
+        // the peer wouldn't read a body there, it'd take it for the start of the next response
+        let allows_body = self.status_code.allows_body() && method != Method::HEAD;
+        let body = self.body.filter(|_| allows_body);
+
+        if body.is_some() && !self.headers.contains_key(&Header::CONTENT_TYPE.into()) {
+            buf.put_slice(b"Content-Type: application/json; charset=utf-8\r\n");
+        }
+
+        match body {
+            Some(Body::Bytes(body)) => {
+                let length = body.len();
+                write!(buf, "Content-Length: {length}\r\n\r\n").expect("No reason to fail.");
+                return body;
+            }
+            Some(Body::Json(body)) => {
+                buf.put_slice(b"Content-Length: ");
+                let length_at = buf.len();
+                // trailing whitespace is allowed after a field value, so it makes a fine padding
+                buf.put_bytes(b' ', CONTENT_LENGTH_WIDTH);
+                buf.put_slice(b"\r\n\r\n");
+
+                let body_at = buf.len();
+                if let Err(e) = body.write_json(buf) {
+                    eprintln!("failed to serialize body; err = {:?}", e);
+                    buf.truncate(body_at);
+                }
+
+                let length = buf.len() - body_at;
+                let mut placeholder = &mut buf[length_at..length_at + CONTENT_LENGTH_WIDTH];
+                std::io::Write::write_fmt(&mut placeholder, format_args!("{length}"))
+                    .expect("Bodies are way smaller than 10 digits.");
+            }
+            None if allows_body => buf.put_slice(b"Content-Length: 0\r\n\r\n"),
+            None => buf.put_slice(b"\r\n"),
+        }
+
+        Bytes::new()
     }
 }
 
@@ -72,11 +351,139 @@ pub struct JsonResponse(pub Response);
 impl JsonResponse {
     pub fn from<T>(body: T) -> Self
     where
-        T: Serialize,
+        T: Serialize + Debug + Send + 'static,
     {
-        let mut response = Response::from_status_code(StatusCode::Ok, None);
-        let body = simd_json::to_string(&body).ok();
-        response.body = body;
-        Self(response)
+        Self(Response::from_status_code(StatusCode::Ok, Body::json(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn converts_from_and_into_http_crate() {
+        for status_code in StatusCode::iter() {
+            let converted = http::StatusCode::from(status_code);
+            assert_eq!(converted.as_u16(), status_code.as_u16());
+            assert_eq!(StatusCode::try_from(converted), Ok(status_code));
+        }
+
+        assert_eq!(
+            StatusCode::try_from(http::StatusCode::IM_A_TEAPOT),
+            Err(http::StatusCode::IM_A_TEAPOT)
+        );
+    }
+
+    #[test]
+    fn well_formed_header_block() {
+        let response = Response::from_status_code(StatusCode::Ok, Body::from("{}"));
+        assert_eq!(
+            response.into_http(true),
+            "HTTP/1.1 200 OK\r\n\
+             Server: localhost\r\n\
+             Connection: keep-alive\r\n\
+             Content-Type: application/json; charset=utf-8\r\n\
+             Content-Length: 2\r\n\r\n{}"
+        );
+
+        let response = Response::from_status_code(StatusCode::NotFound, None);
+        assert_eq!(
+            response.into_http(false),
+            "HTTP/1.1 404 Not Found\r\n\
+             Server: localhost\r\n\
+             Connection: close\r\n\
+             Content-Length: 0\r\n\r\n"
+        );
+
+        let response = Response::from_status_code(StatusCode::NoContent, None);
+        assert!(response.into_http(true).ends_with(b"keep-alive\r\n\r\n"));
+    }
+
+    #[test]
+    fn bodies_only_where_allowed() {
+        let response = Response::from_status_code(StatusCode::NoContent, Body::from("{}"));
+        assert_eq!(
+            response.into_http(true),
+            "HTTP/1.1 204 No Content\r\n\
+             Server: localhost\r\n\
+             Connection: keep-alive\r\n\r\n"
+        );
+
+        let mut buf = BytesMut::new();
+        let response = Response::from_status_code(StatusCode::Ok, Body::from("{}"));
+        let body = response.write_http(&mut buf, true, Method::HEAD);
+        assert!(body.is_empty());
+        assert!(buf.ends_with(b"keep-alive\r\n\r\n"));
+
+        buf.clear();
+        let response = JsonResponse::from(vec![1, 2]).0;
+        let body = response.write_http(&mut buf, true, Method::HEAD);
+        assert!(body.is_empty());
+        assert!(buf.ends_with(b"keep-alive\r\n\r\n"));
+    }
+
+    #[test]
+    fn writes_custom_headers() {
+        let response = Response::from_status_code(StatusCode::Created, None)
+            .with_header(Header::LOCATION, "/clientes/6")
+            .with_header("X-Custom", "yes")
+            .with_header(Header::CONTENT_LENGTH, "100")
+            .with_header("X-Injected", "a\r\nSet-Cookie: b");
+        let http = response.into_http(true);
+        let http = std::str::from_utf8(&http).unwrap();
+
+        assert!(http.contains("\r\nlocation: /clientes/6\r\n"));
+        assert!(http.contains("\r\nx-custom: yes\r\n"));
+        assert!(http.ends_with("\r\nContent-Length: 0\r\n\r\n"));
+        assert!(!http.contains("100"));
+        assert!(!http.contains("Set-Cookie"));
+
+        let response = Response::from_status_code(StatusCode::Ok, Body::from("hi"))
+            .with_header(Header::CONTENT_TYPE, "text/plain");
+        let http = response.into_http(true);
+        let http = std::str::from_utf8(&http).unwrap();
+        assert!(http.contains("\r\ncontent-type: text/plain\r\n"));
+        assert!(!http.contains("application/json"));
+    }
+
+    #[test]
+    fn json_body_in_place() {
+        #[derive(Debug, Serialize)]
+        struct Sample {
+            saldo: i32,
+        }
+
+        let mut buf = BytesMut::from(&b"leftover"[..]);
+        let response = JsonResponse::from(Sample { saldo: -10 }).0;
+        let body = response.write_http(&mut buf, true, Method::POST);
+
+        assert!(body.is_empty());
+        assert!(buf.starts_with(b"leftoverHTTP/1.1 200 OK\r\n"));
+        assert!(buf.ends_with(b"\r\nContent-Length: 13        \r\n\r\n{\"saldo\":-10}"));
+
+        let mut headers = [httparse::EMPTY_HEADER; 8];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let head_len = parsed.parse(&buf[b"leftover".len()..]).unwrap().unwrap();
+        let length = parsed
+            .headers
+            .iter()
+            .find(|h| h.name == "Content-Length")
+            .map(|h| std::str::from_utf8(h.value).unwrap().trim())
+            .unwrap();
+        assert_eq!(length, "13");
+        assert_eq!(buf.len() - b"leftover".len() - head_len, 13);
+    }
+
+    #[test]
+    fn standard_reason_phrases() {
+        assert_eq!(StatusCode::Ok.reason_phrase(), "OK");
+        assert_eq!(StatusCode::NotFound.reason_phrase(), "Not Found");
+        assert_eq!(
+            StatusCode::UnprocessableEntity.reason_phrase(),
+            "Unprocessable Content"
+        );
+        assert_eq!(StatusCode::TooManyRequests.as_u16(), 429);
     }
 }
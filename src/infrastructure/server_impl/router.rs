@@ -0,0 +1,222 @@
+//! Path router, maps `(Method, path pattern)` pairs to whatever identifies an endpoint.
+
+use crate::infrastructure::server_impl::errors::HttpError;
+use crate::infrastructure::server_impl::server::Method;
+use compact_str::CompactString;
+use std::str::FromStr;
+
+/// Most path parameters a single pattern may declare.
+const MAX_PARAMS: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+#[derive(Debug)]
+struct Route<T> {
+    method: Method,
+    segments: Vec<Segment>,
+    target: T,
+}
+
+#[derive(Debug)]
+pub struct Router<T> {
+    routes: Vec<Route<T>>,
+}
+
+/// Path parameters captured while matching, borrowed from the request.
+#[derive(Debug, Default)]
+pub struct Params<'a> {
+    params: [(&'static str, &'a str); MAX_PARAMS],
+    len: usize,
+}
+
+impl<'a> Params<'a> {
+    pub fn get_raw(&self, name: &str) -> Option<&'a str> {
+        self.params[..self.len]
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, val)| *val)
+    }
+
+    /// Parses the parameter, anything that doesn't fit the type is a path that doesn't exist.
+    pub fn get<V: FromStr>(&self, name: &str) -> Result<V, HttpError> {
+        self.get_raw(name)
+            .and_then(|val| V::from_str(val).ok())
+            .ok_or(HttpError::NotFound("Route not found."))
+    }
+
+    fn push(&mut self, name: &'static str, val: &'a str) {
+        self.params[self.len] = (name, val);
+        self.len += 1;
+    }
+}
+
+/// Path without the query string.
+pub fn strip_query(resource: &str) -> &str {
+    resource.split_once('?').map_or(resource, |(path, _)| path)
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.trim_start_matches('/').split('/')
+}
+
+impl<T: Copy> Router<T> {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Registers `pattern`, where `{name}` segments capture a path parameter, e.g.
+    /// `/clientes/{id}/extrato`.
+    pub fn route(mut self, method: Method, pattern: &'static str, target: T) -> Self {
+        let segments = segments(pattern)
+            .map(
+                |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(name) => Segment::Param(name),
+                    None => Segment::Literal(segment),
+                },
+            )
+            .collect::<Vec<_>>();
+        assert!(
+            segments
+                .iter()
+                .filter(|s| matches!(s, Segment::Param(_)))
+                .count()
+                <= MAX_PARAMS,
+            "Too many path parameters in {pattern}."
+        );
+
+        self.routes.push(Route {
+            method,
+            segments,
+            target,
+        });
+        self
+    }
+
+    /// Finds the endpoint for `resource`, ignoring its query string.
+    ///
+    /// Fails with 404 when no pattern matches the path, and with 405 listing the allowed methods
+    /// when some pattern does but not for `method`.
+    pub fn find<'a>(
+        &self,
+        method: Method,
+        resource: &'a str,
+    ) -> Result<(T, Params<'a>), HttpError> {
+        let path = strip_query(resource);
+        let mut allowed: Option<CompactString> = None;
+
+        for route in &self.routes {
+            let Some(params) = route.matches(path) else {
+                continue;
+            };
+            if route.method == method {
+                return Ok((route.target, params));
+            }
+
+            let allowed = allowed.get_or_insert_with(CompactString::default);
+            if !allowed.is_empty() {
+                allowed.push_str(", ");
+            }
+            allowed.push_str(route.method.into());
+        }
+
+        match allowed {
+            Some(allowed) => Err(HttpError::MethodNotAllowed(allowed)),
+            None => Err(HttpError::NotFound("Route not found.")),
+        }
+    }
+}
+
+impl<T: Copy> Default for Router<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Route<T> {
+    fn matches<'a>(&self, path: &'a str) -> Option<Params<'a>> {
+        let mut params = Params::default();
+        let mut path_segments = segments(path);
+
+        for segment in &self.segments {
+            let val = path_segments.next()?;
+            match segment {
+                Segment::Literal(literal) if *literal == val => {}
+                Segment::Param(name) if !val.is_empty() => params.push(name, val),
+                _ => return None,
+            }
+        }
+
+        path_segments.next().is_none().then_some(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    enum Endpoint {
+        Statement,
+        Transaction,
+        Account,
+    }
+
+    fn router() -> Router<Endpoint> {
+        Router::new()
+            .route(Method::GET, "/clientes/{id}/extrato", Endpoint::Statement)
+            .route(
+                Method::POST,
+                "/clientes/{id}/transacoes",
+                Endpoint::Transaction,
+            )
+            .route(Method::GET, "/clientes/{id}", Endpoint::Account)
+            .route(Method::PUT, "/clientes/{id}", Endpoint::Account)
+    }
+
+    #[test]
+    fn success_with_params_and_query() {
+        let (endpoint, params) = router()
+            .find(Method::GET, "/clientes/123/extrato?limit=50")
+            .unwrap();
+        assert_eq!(endpoint, Endpoint::Statement);
+        assert_eq!(params.get::<i32>("id").unwrap(), 123);
+        assert_eq!(params.get_raw("missing"), None);
+
+        let (endpoint, _) = router().find(Method::GET, "/clientes/1").unwrap();
+        assert_eq!(endpoint, Endpoint::Account);
+    }
+
+    #[test]
+    fn failure_not_found() {
+        for path in [
+            "/",
+            "/clientes",
+            "/clientes//extrato",
+            "/prefix/clientes/1/extrato",
+            "/clientes/1/extrato/suffix",
+        ] {
+            match router().find(Method::GET, path) {
+                Err(HttpError::NotFound(_)) => {}
+                other => panic!("{path} matched {other:?}"),
+            }
+        }
+
+        let (_, params) = router().find(Method::GET, "/clientes/abc/extrato").unwrap();
+        assert!(matches!(
+            params.get::<i32>("id"),
+            Err(HttpError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn failure_method_not_allowed() {
+        match router().find(Method::DELETE, "/clientes/1") {
+            Err(HttpError::MethodNotAllowed(allowed)) => assert_eq!(allowed, "GET, PUT"),
+            other => panic!("matched {other:?}"),
+        }
+    }
+}
@@ -1,53 +1,94 @@
+use std::borrow::Cow;
 use std::str::FromStr;
 use std::sync::OnceLock;
 
-use crate::AnyResult;
-use either::Either;
+use compact_str::CompactString;
 use enum_map::{Enum, EnumMap};
 use httparse::{ParserConfig, Status};
-use memchr::memchr;
-use regex_lite::Regex;
 use strum::{EnumIter, EnumString, IntoEnumIterator, IntoStaticStr};
 
-use crate::api::{statement_route, transaction_route};
+use crate::api::{
+    account_route, close_account_route, credit_limit_route, open_account_route, reversal_route,
+    statement_route, transaction_lookup_route, transaction_route, transfer_route,
+};
 use crate::application::ServerData;
+use crate::infrastructure::server_impl::chunked::ChunkedDecoder;
+use crate::infrastructure::server_impl::errors::HttpError;
 use crate::infrastructure::server_impl::request::Request;
-use crate::infrastructure::server_impl::response::{Response, StatusCode};
+use crate::infrastructure::server_impl::response::Response;
+use crate::infrastructure::server_impl::router::Router;
 
-static ROUTER: OnceLock<Regex> = OnceLock::new();
+static ROUTER: OnceLock<Router<Endpoint>> = OnceLock::new();
 
-pub fn get_router() -> &'static Regex {
-    ROUTER.get_or_init(|| Regex::new(r#"clientes/(\d)/(transacoes|extrato)"#).unwrap())
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    OpenAccount,
+    Account,
+    CreditLimit,
+    CloseAccount,
+    Statement,
+    Transaction,
+    TransactionLookup,
+    Reversal,
+    Transfer,
+}
+
+pub fn get_router() -> &'static Router<Endpoint> {
+    ROUTER.get_or_init(|| {
+        Router::new()
+            .route(Method::POST, "/clientes", Endpoint::OpenAccount)
+            .route(Method::GET, "/clientes/{id}", Endpoint::Account)
+            .route(Method::DELETE, "/clientes/{id}", Endpoint::CloseAccount)
+            .route(
+                Method::PATCH,
+                "/clientes/{id}/limite",
+                Endpoint::CreditLimit,
+            )
+            .route(Method::GET, "/clientes/{id}/extrato", Endpoint::Statement)
+            .route(
+                Method::POST,
+                "/clientes/{id}/transacoes",
+                Endpoint::Transaction,
+            )
+            .route(
+                Method::GET,
+                "/clientes/{id}/transacoes/{transacao}",
+                Endpoint::TransactionLookup,
+            )
+            .route(
+                Method::POST,
+                "/clientes/{id}/transacoes/{transacao}/estorno",
+                Endpoint::Reversal,
+            )
+            .route(Method::POST, "/transferencias", Endpoint::Transfer)
+    })
 }
 
 pub async fn match_routes(
     server_data: &ServerData,
     request: Request<'_>,
-) -> Either<Response, Response> {
-    let Some(route) = get_router().captures(request.resource) else {
-        return Either::Right(Response::from_status_code(
-            StatusCode::NotFound,
-            "route error".to_string(),
-        ));
-    };
-    let client_id = route
-        .get(1)
-        .and_then(|c| i32::from_str(c.as_str()).ok())
-        .unwrap();
-
-    // the fastest router in existence!
-    let response = match route.get(2).map(|c| c.as_str()).unwrap() {
-        "extrato" => statement_route(server_data, request, client_id).await,
-        "transacoes" => transaction_route(server_data, request, client_id).await,
-        _ => {
-            return Either::Right(Response::from_status_code(
-                StatusCode::NotFound,
-                "route not found".to_string(),
-            ))
-        }
-    };
+) -> Result<Response, HttpError> {
+    let (endpoint, params) = get_router().find(request.method, request.resource)?;
 
-    Either::Left(response.unwrap())
+    match endpoint {
+        Endpoint::OpenAccount => open_account_route(server_data, request).await,
+        Endpoint::Account => account_route(server_data, request, params.get("id")?).await,
+        Endpoint::CreditLimit => credit_limit_route(server_data, request, params.get("id")?).await,
+        Endpoint::CloseAccount => {
+            close_account_route(server_data, request, params.get("id")?).await
+        }
+        Endpoint::Statement => statement_route(server_data, request, params.get("id")?).await,
+        Endpoint::Transaction => transaction_route(server_data, request, params.get("id")?).await,
+        Endpoint::TransactionLookup => {
+            let (id, transaction) = (params.get("id")?, params.get("transacao")?);
+            transaction_lookup_route(server_data, request, id, transaction).await
+        }
+        Endpoint::Reversal => {
+            let (id, transaction) = (params.get("id")?, params.get("transacao")?);
+            reversal_route(server_data, request, id, transaction).await
+        }
+        Endpoint::Transfer => transfer_route(server_data, request).await,
+    }
 }
 
 #[allow(clippy::upper_case_acronyms, non_camel_case_types)]
@@ -66,8 +107,68 @@ pub enum Header {
     CONNECTION,
     #[strum(serialize = "host")]
     HOST,
+    #[strum(serialize = "idempotency-key")]
+    IDEMPOTENCY_KEY,
+    #[strum(serialize = "transfer-encoding")]
+    TRANSFER_ENCODING,
     #[strum(serialize = "user-agent")]
     USER_AGENT,
+    #[strum(serialize = "x-request-id")]
+    X_REQUEST_ID,
+
+    // response only
+    #[strum(serialize = "allow")]
+    ALLOW,
+    #[strum(serialize = "cache-control")]
+    CACHE_CONTROL,
+    #[strum(serialize = "date")]
+    DATE,
+    #[strum(serialize = "etag")]
+    ETAG,
+    #[strum(serialize = "last-modified")]
+    LAST_MODIFIED,
+    #[strum(serialize = "location")]
+    LOCATION,
+    #[strum(serialize = "retry-after")]
+    RETRY_AFTER,
+    #[strum(serialize = "vary")]
+    VARY,
+}
+
+impl Header {
+    pub fn as_str(self) -> &'static str {
+        self.into()
+    }
+}
+
+/// Any header name, for the ones [Header] doesn't know about.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HeaderName {
+    Known(Header),
+    Other(CompactString),
+}
+
+impl HeaderName {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HeaderName::Known(header) => header.as_str(),
+            HeaderName::Other(name) => name.as_str(),
+        }
+    }
+}
+
+impl From<Header> for HeaderName {
+    fn from(value: Header) -> Self {
+        HeaderName::Known(value)
+    }
+}
+
+impl From<&str> for HeaderName {
+    fn from(value: &str) -> Self {
+        Header::from_str(value)
+            .map(HeaderName::Known)
+            .unwrap_or_else(|_| HeaderName::Other(value.to_ascii_lowercase().into()))
+    }
 }
 
 impl FromStr for Header {
@@ -85,8 +186,17 @@ pub enum Method {
     DELETE,
     GET,
     HEAD,
+    OPTIONS,
+    PATCH,
     POST,
     PUT,
+    TRACE,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
 }
 
 impl TryFrom<&[u8]> for Method {
@@ -98,31 +208,70 @@ impl TryFrom<&[u8]> for Method {
     }
 }
 
+/// Chunked must be the final encoding applied, see RFC 9112 section 6.1.
+fn is_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|last| unicase::eq(last.trim(), "chunked"))
+}
+
 fn to_str(str_like: &[u8]) -> &str {
     unsafe { std::str::from_utf8_unchecked(str_like) }
 }
 
-fn parse_body(body: &[u8]) -> Option<&[u8]> {
-    if body.is_empty() || body.first() == Some(&b'\0') {
-        return None;
-    }
+/// Default upper bound for a single request body, either announced through `Content-Length` or
+/// decoded from chunks.
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+const MAX_HEADERS: usize = 16;
 
-    let body_content = memchr(b'\0', body).map(|idx| &body[..idx]).unwrap_or(body);
-    Some(body_content)
+/// Parses the first request in `request`, see [parse_http_with].
+pub fn parse_http(request: &[u8]) -> Result<Option<(Request<'_>, usize)>, HttpError> {
+    parse_http_with(request, MAX_BODY_SIZE, &mut ChunkedDecoder::default())
 }
 
-/// Returns a [Request]
-/// Won't handle anything else than a simple request.
-/// And probably explode if anything else than a well-formed request is parsed.
-pub fn parse_http(request: &[u8]) -> AnyResult<Request> {
-    let mut headers = [httparse::EMPTY_HEADER; 7];
+/// Parses the first request in `request`, refusing bodies bigger than `max_body_size`.
+///
+/// Returns `None` when the buffer still doesn't hold a complete request, so the caller should read
+/// more bytes and try again. Otherwise returns the [Request] alongside the number of bytes it
+/// occupies in the buffer, anything after that belongs to the next (pipelined) request.
+///
+/// `chunked` keeps the progress through a chunked body between those tries, so it must be the same
+/// decoder until the request is complete.
+pub fn parse_http_with<'a>(
+    request: &'a [u8],
+    max_body_size: usize,
+    chunked: &mut ChunkedDecoder,
+) -> Result<Option<(Request<'a>, usize)>, HttpError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let body = ParserConfig::default()
-        .parse_request(&mut req, request)
-        .unwrap();
+    let head_len = match ParserConfig::default().parse_request(&mut req, request) {
+        Ok(Status::Complete(idx)) => idx,
+        Ok(Status::Partial) => return Ok(None),
+        Err(_) => return Err(HttpError::BadRequest("Malformed request head.")),
+    };
 
-    let method = Method::from_str(req.method.unwrap()).unwrap();
-    let resource = req.path.unwrap();
+    let method = Method::from_str(req.method.unwrap_or_default())
+        .map_err(|_| HttpError::BadRequest("Unsupported method."))?;
+    let resource = req.path.unwrap_or_default();
+    let version = match req.version {
+        Some(0) => Version::Http10,
+        _ => Version::Http11,
+    };
+
+    // the map keeps the last of repeated headers, lengths that disagree would leave the body's end
+    // up to whoever reads the request next: a request smuggling vector, see RFC 9112 section 6.3
+    let mut lengths = req
+        .headers
+        .iter()
+        .filter(|c| unicase::eq(c.name, Header::CONTENT_LENGTH.as_str()))
+        .map(|c| c.value.trim_ascii());
+    if let Some(first) = lengths.next() {
+        if lengths.any(|length| length != first) {
+            return Err(HttpError::BadRequest("Conflicting Content-Length values."));
+        }
+    }
 
     let headers = req
         .headers
@@ -130,17 +279,52 @@ pub fn parse_http(request: &[u8]) -> AnyResult<Request> {
         .filter_map(|c| Header::from_str(c.name).ok().map(|h| (h, to_str(c.value))))
         .collect::<EnumMap<_, _>>();
 
-    let body = match body {
-        Status::Complete(idx) => parse_body(&request[idx..]),
-        Status::Partial => unimplemented!(),
+    let (body, request_len) = match (
+        headers[Header::TRANSFER_ENCODING],
+        headers[Header::CONTENT_LENGTH],
+    ) {
+        ("", "") => (None, head_len),
+        ("", length) => {
+            let content_length = usize::from_str(length.trim())
+                .map_err(|_| HttpError::BadRequest("Invalid Content-Length."))?;
+            if content_length > max_body_size {
+                return Err(HttpError::ContentTooLarge);
+            }
+
+            let request_len = head_len + content_length;
+            if request.len() < request_len {
+                return Ok(None);
+            }
+            let body = &request[head_len..request_len];
+            (Some(Cow::Borrowed(body)), request_len)
+        }
+        (encoding, "") if is_chunked(encoding) => {
+            let Some((body, body_len)) = chunked.decode(&request[head_len..], max_body_size)?
+            else {
+                return Ok(None);
+            };
+            (Some(Cow::Owned(body)), head_len + body_len)
+        }
+        (_, "") => return Err(HttpError::BadRequest("Unsupported transfer encoding.")),
+        // a request smuggling vector, see RFC 9112 section 6.3
+        (_, _) => {
+            return Err(HttpError::BadRequest(
+                "Both Transfer-Encoding and Content-Length were sent.",
+            ))
+        }
     };
+    let body = body.filter(|body| !body.is_empty());
 
-    Ok(Request {
-        method,
-        resource,
-        headers,
-        body,
-    })
+    Ok(Some((
+        Request {
+            method,
+            version,
+            resource,
+            headers,
+            body,
+        },
+        request_len,
+    )))
 }
 
 #[cfg(test)]
@@ -149,26 +333,151 @@ mod tests {
 
     #[test]
     fn success_with_body() {
-        let sample = b"GET /somepath HTTP/1.1\nHost: ifconfig.me\nUser-Agent: curl/8.5.0\nAccept: */*\nContent-Type: text/html; charset=ISO-8859-4\r\n\r\n{\"json_key\": 10}";
+        let sample = b"GET /somepath HTTP/1.1\nHost: ifconfig.me\nUser-Agent: curl/8.5.0\nAccept: */*\nContent-Type: text/html; charset=ISO-8859-4\nContent-Length: 16\r\n\r\n{\"json_key\": 10}";
 
-        let request = parse_http(sample).unwrap();
+        let (request, consumed) = parse_http(sample).unwrap().unwrap();
+        assert_eq!(consumed, sample.len());
         assert_eq!(request.method, Method::GET);
         assert_eq!(request.resource, "/somepath");
         assert_eq!(
             request.headers[Header::CONTENT_TYPE],
             "text/html; charset=ISO-8859-4"
         );
-        assert_eq!(to_str(request.body.unwrap()), r#"{"json_key": 10}"#);
+        assert_eq!(to_str(&request.body.unwrap()), r#"{"json_key": 10}"#);
     }
 
     #[test]
     fn success_without_body() {
         let sample = b"GET /somepath HTTP/1.1\nHost: ifconfig.me\nUser-Agent: curl/8.5.0\nAccept: */*\nContent-Type: text/html; charset=ISO-8859-4\r\n\r\n";
 
-        let request = parse_http(sample).unwrap();
+        let (request, _) = parse_http(sample).unwrap().unwrap();
         assert_eq!(request.method, Method::GET);
         assert_eq!(request.resource, "/somepath");
         assert_eq!(request.headers[Header::HOST], "ifconfig.me");
         assert_eq!(request.body, None);
     }
+
+    #[test]
+    fn partial_head_and_body() {
+        let sample =
+            b"POST /clientes/1/transacoes HTTP/1.1\r\nContent-Length: 10\r\n\r\n{\"valor\":1}";
+
+        assert!(parse_http(&sample[..20]).unwrap().is_none());
+        assert!(parse_http(&sample[..sample.len() - 2]).unwrap().is_none());
+
+        let (request, consumed) = parse_http(sample).unwrap().unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"{\"valor\":1"[..]));
+        assert_eq!(consumed, sample.len() - 1);
+    }
+
+    #[test]
+    fn pipelined_requests_dont_share_body() {
+        let sample = b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}GET /b HTTP/1.1\r\n\r\n";
+
+        let (first, consumed) = parse_http(sample).unwrap().unwrap();
+        assert_eq!(first.resource, "/a");
+        assert_eq!(first.body.as_deref(), Some(&b"{}"[..]));
+
+        let (second, rest) = parse_http(&sample[consumed..]).unwrap().unwrap();
+        assert_eq!(second.resource, "/b");
+        assert_eq!(second.body, None);
+        assert_eq!(consumed + rest, sample.len());
+    }
+
+    #[test]
+    fn failure_invalid_content_length() {
+        let sample = b"POST /a HTTP/1.1\r\nContent-Length: nope\r\n\r\n";
+        assert!(parse_http(sample).is_err());
+    }
+
+    #[test]
+    fn repeated_content_length() {
+        let sample = b"POST /a HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 5\r\n\r\n{}GET /";
+        assert!(matches!(
+            parse_http(sample),
+            Err(HttpError::BadRequest("Conflicting Content-Length values."))
+        ));
+
+        let sample = b"POST /a HTTP/1.1\r\nContent-Length: 2\r\nContent-Length:  2\r\n\r\n{}";
+        let (request, consumed) = parse_http(sample).unwrap().unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"{}"[..]));
+        assert_eq!(consumed, sample.len());
+    }
+
+    #[test]
+    fn success_chunked_body() {
+        let sample = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+        assert!(parse_http(sample).is_err());
+
+        let sample = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4\r\n\":1}\r\n0\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+
+        let (request, consumed) = parse_http(sample).unwrap().unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"{\"a\":1}"[..]));
+        assert_eq!(&sample[consumed..], b"GET /b HTTP/1.1\r\n\r\n");
+        assert!(parse_http(&sample[..consumed - 1]).unwrap().is_none());
+    }
+
+    #[test]
+    fn failure_chunked_framing() {
+        let sample = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{}\r\n0\r\n\r\n";
+        assert!(parse_http(sample).is_err());
+
+        let sample = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 2\r\n\r\n";
+        assert!(parse_http(sample).is_err());
+
+        let sample =
+            b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nabcde\r\n0\r\n\r\n";
+        assert!(parse_http_with(sample, 4, &mut ChunkedDecoder::default()).is_err());
+    }
+
+    #[test]
+    fn connection_persistence() {
+        let keep_alive = |sample: &[u8]| parse_http(sample).unwrap().unwrap().0.keep_alive();
+
+        assert!(keep_alive(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(
+            b"GET / HTTP/1.0\r\nConnection: upgrade, Keep-Alive\r\n\r\n"
+        ));
+    }
+
+    #[test]
+    fn success_query_string() {
+        let sample = b"GET /clientes/1/extrato?limit=50&descricao=p%C3%A3o HTTP/1.1\r\n\r\n";
+
+        let (request, _) = parse_http(sample).unwrap().unwrap();
+        assert_eq!(request.path(), "/clientes/1/extrato");
+        assert_eq!(request.query().parse::<u32>("limit").unwrap(), Some(50));
+        assert_eq!(request.query().get("descricao").as_deref(), Some("pão"));
+
+        let (request, _) = parse_http(b"GET /clientes/1/extrato HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.query().pairs().count(), 0);
+    }
+
+    #[test]
+    fn account_endpoints() {
+        let router = get_router();
+        for (method, path, expected) in [
+            (Method::POST, "/clientes", Endpoint::OpenAccount),
+            (Method::GET, "/clientes/1", Endpoint::Account),
+            (Method::DELETE, "/clientes/1", Endpoint::CloseAccount),
+            (Method::PATCH, "/clientes/1/limite", Endpoint::CreditLimit),
+            (
+                Method::GET,
+                "/clientes/1/transacoes/018bcfe5-687b-7000-8000-000000000000",
+                Endpoint::TransactionLookup,
+            ),
+        ] {
+            let (endpoint, _) = router.find(method, path).unwrap();
+            assert_eq!(endpoint, expected);
+        }
+
+        match router.find(Method::PUT, "/clientes/1") {
+            Err(HttpError::MethodNotAllowed(allowed)) => assert_eq!(allowed, "GET, DELETE"),
+            other => panic!("matched {other:?}"),
+        }
+    }
 }
@@ -7,14 +7,15 @@ pub mod domain;
 pub mod application;
 pub mod infrastructure;
 
+use crate::domain::money::Money;
 use serde::Deserialize;
 use time::OffsetDateTime;
 
 #[derive(Debug, Copy, Clone, Deserialize)]
 pub struct Statement {
-    pub balance: i32,
+    pub balance: Money,
     pub time_of_statement: OffsetDateTime,
-    pub credit_limit: u32,
+    pub credit_limit: Money,
 }
 
 pub type AnyResult<T> = eyre::Result<T>;